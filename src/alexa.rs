@@ -1,98 +1,258 @@
 use std::sync::{Arc, Mutex};
 
-use log::error;
-use samp::amx::{Amx, AmxIdent};
-use samp::cell::AmxString;
+use log::warn;
+use samp::amx::{Allocator, Amx};
+use samp::cell::{AmxString, UnsizedBuffer};
 use samp::error::{AmxError, AmxResult};
 use samp::native;
-use slab::Slab;
 
-pub struct AlexaJob {
-    job_completed: bool,
-    ident: AmxIdent,
-    _query: String,
-    player_id: u32,
-    offset: u32,
-    response: Option<String>,
+use crate::alexa_session::{ChatMessage, SessionContext, SessionStore};
+use crate::http::client;
+use crate::intents::{find_slot, format_slots, IntentEngine};
+use crate::jobs::{push_error, ErrorCode, Job, JobError};
+
+#[derive(Clone, Copy, PartialEq, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlexaBackend {
+    /// Only the intent file and the fallback reply.
+    Intents,
+    /// Unmatched queries go to an OpenAI compatible chat completion endpoint.
+    Chat,
 }
 
-pub struct AlexaPlugin {
-    pub jobs: Arc<Mutex<Slab<AlexaJob>>>,
+#[derive(Clone, serde_derive::Deserialize)]
+#[serde(default)]
+pub struct AlexaConfig {
+    /// Intent definitions, see `intents::IntentFile` for the layout.
+    pub intents_file: String,
+    /// Reply used when no intent matches, the backend fails or its reply is blocked.
+    pub fallback: String,
+    pub backend: AlexaBackend,
+    pub chat_endpoint: String,
+    pub api_key: String,
+    pub model: String,
+    pub system_prompt: String,
+    pub max_tokens: u32,
+    /// Replies are cut to this many characters to fit a chat line.
+    pub max_reply_length: usize,
+    /// Previous messages per player sent along with each query, rounded down to whole exchanges.
+    pub history_length: usize,
+    /// Seconds of silence after which a player's session starts over.
+    pub session_expiry_secs: u64,
+    /// Replies containing any of these words are replaced with the fallback.
+    pub blocked_words: Vec<String>,
 }
 
-impl AlexaPlugin {
-    fn executor(params: &mut AlexaJob) -> Result<(), Box<dyn std::error::Error>> {
-        params.response = Some("Hi, this is an invalid instruction. See /help.".to_string());
+impl Default for AlexaConfig {
+    fn default() -> Self {
+        AlexaConfig {
+            intents_file: "alexa_intents.toml".to_string(),
+            fallback: "Hi, this is an invalid instruction. See /help.".to_string(),
+            backend: AlexaBackend::Intents,
+            chat_endpoint: "http://localhost:8080/v1/chat/completions".to_string(),
+            api_key: String::new(),
+            model: "local".to_string(),
+            system_prompt: "You are Alexa, a helpful assistant on the Indian Ocean Roleplay \
+                            SA-MP server. Answer in one short sentence."
+                .to_string(),
+            max_tokens: 96,
+            max_reply_length: 140,
+            history_length: 6,
+            session_expiry_secs: 600,
+            blocked_words: Vec::new(),
+        }
+    }
+}
 
-        Ok(())
+#[derive(serde_derive::Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: Vec<ChatMessage>,
+    max_tokens: u32,
+}
+
+#[derive(serde_derive::Deserialize)]
+struct ChatChoice {
+    message: ChatMessage,
+}
+
+#[derive(serde_derive::Deserialize)]
+struct ChatResponse {
+    choices: Vec<ChatChoice>,
+}
+
+/// Flattens a reply onto one line and cuts it to `max_length` characters.
+fn clean_reply(reply: &str, max_length: usize) -> String {
+    let line = reply.split_whitespace().collect::<Vec<&str>>().join(" ");
+    if line.chars().count() <= max_length {
+        return line;
     }
 
-    pub fn add_job(&mut self, amx: &Amx, player_id: u32, query: AmxString, offset: u32) {
-        let ident = AmxIdent::from(amx.amx().as_ptr());
+    // Too short to leave room for the ellipsis
+    if max_length < 4 {
+        return line.chars().take(max_length).collect();
+    }
 
-        let data = AlexaJob {
-            job_completed: false,
-            ident,
-            _query: query.to_string(),
-            player_id,
-            offset,
-            response: None,
-        };
+    let mut cut: String = line.chars().take(max_length - 3).collect();
+    cut.push_str("...");
+    cut
+}
 
-        let key = self.jobs.lock().unwrap().insert(data);
-        let arc_key = Arc::new(key);
-        let share_arc_key = Arc::clone(&arc_key);
-        let slab_handle = Arc::clone(&self.jobs);
+fn is_blocked(reply: &str, blocked_words: &[String]) -> bool {
+    reply.split(|c: char| !c.is_alphanumeric()).any(|word| {
+        blocked_words
+            .iter()
+            .any(|blocked| word.eq_ignore_ascii_case(blocked))
+    })
+}
+
+pub struct AlexaReply {
+    text: String,
+    intent: String,
+    slots: Vec<(String, String)>,
+}
 
-        std::thread::spawn(move || {
-            let mut slab = slab_handle.lock().unwrap();
-            let key = *share_arc_key;
-            let params = slab.get_mut(key).unwrap();
+pub struct AlexaJob {
+    engine: Arc<IntentEngine>,
+    config: AlexaConfig,
+    sessions: Arc<Mutex<SessionStore>>,
+    query: String,
+    player_id: u32,
+    offset: u32,
+    response: Option<AlexaReply>,
+}
 
-            match AlexaPlugin::executor(params) {
-                Err(_e) => error!("{}", _e),
-                Ok(_) => (),
-            };
+impl AlexaJob {
+    fn request_chat(&self, context: &SessionContext) -> Result<String, JobError> {
+        let mut messages = vec![ChatMessage::new("system", &self.config.system_prompt)];
+        messages.extend(context.messages.iter().cloned());
+        messages.push(ChatMessage::new("user", &self.query));
 
-            params.job_completed = true;
-        });
+        let request = ChatRequest {
+            model: &self.config.model,
+            messages,
+            max_tokens: self.config.max_tokens,
+        };
+
+        let mut builder = client().post(&self.config.chat_endpoint).json(&request);
+        if !self.config.api_key.is_empty() {
+            builder = builder.bearer_auth(&self.config.api_key);
+        }
+
+        let response: ChatResponse = builder.send()?.error_for_status()?.json()?;
+        response
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.message.content)
+            .ok_or_else(|| JobError::new(ErrorCode::Parse, "reply has no choices"))
     }
 
-    pub fn process_tick(&mut self) -> Result<(), AmxError> {
-        let mut slab = self.jobs.lock().map_err(|_| AmxError::NotFound)?;
+    /// Asks the chat backend, `None` on failure or a blocked reply.
+    fn chat_reply(&self, context: &SessionContext) -> Option<String> {
+        let reply = match self.request_chat(context) {
+            Ok(reply) => clean_reply(&reply, self.config.max_reply_length),
+            Err(_e) => {
+                warn!("Alexa: chat backend failed: {}", _e);
+                return None;
+            }
+        };
 
-        // Collect keys of jobs to be removed
-        let mut to_remove = Vec::new();
+        if reply.is_empty() || is_blocked(&reply, &self.config.blocked_words) {
+            return None;
+        }
 
-        for (_key, params) in slab.iter_mut() {
-            if params.job_completed {
-                let amx = samp::amx::get(params.ident).ok_or(AmxError::NotFound)?;
-                let index = amx.find_public("OnAlexaReply")?;
-                let allocator = amx.allocator();
+        Some(reply)
+    }
+}
+
+impl Job for AlexaJob {
+    const CALLBACK: &'static str = "OnAlexaReply";
+    const ERROR_CALLBACK: &'static str = "OnAlexaError";
+    const KIND: &'static str = "alexa";
 
-                let push_string = |value: &Option<String>| {
-                    let binding = "NaN".to_string();
-                    let str: &String = value.as_ref().unwrap_or(&binding);
-                    amx.push(allocator.allot_string(str)?)
-                };
+    fn player_id(&self) -> Option<u32> {
+        Some(self.player_id)
+    }
 
-                amx.push(params.offset)?;
-                push_string(&params.response)?;
-                amx.push(params.player_id)?;
-                amx.exec(index)?;
+    fn executor(&mut self) -> Result<(), JobError> {
+        let context = self
+            .sessions
+            .lock()
+            .map(|mut sessions| sessions.context(self.player_id))
+            .unwrap_or_default();
 
-                // Add key to to_remove
-                to_remove.push(_key);
+        let matched =
+            self.engine
+                .resolve(&self.query, context.last_intent.as_deref(), &context.slots);
+
+        // Fallbacks and blocked replies stay out of the history
+        let (reply, answered) = match matched {
+            Some(matched) => {
+                let answered = matched.response.is_some();
+                (
+                    AlexaReply {
+                        text: matched
+                            .response
+                            .unwrap_or_else(|| self.config.fallback.clone()),
+                        intent: matched.name,
+                        slots: matched.slots,
+                    },
+                    answered,
+                )
             }
-        }
+            None if self.config.backend == AlexaBackend::Chat => {
+                let text = self.chat_reply(&context);
+                let answered = text.is_some();
+                (
+                    AlexaReply {
+                        text: text.unwrap_or_else(|| self.config.fallback.clone()),
+                        intent: "chat".to_string(),
+                        slots: Vec::new(),
+                    },
+                    answered,
+                )
+            }
+            None => (
+                AlexaReply {
+                    text: self.config.fallback.clone(),
+                    intent: "none".to_string(),
+                    slots: Vec::new(),
+                },
+                false,
+            ),
+        };
 
-        // Remove processed items
-        for key in to_remove {
-            slab.remove(key);
+        // A matched intent without a response still counts for follow-ups
+        let intent = match reply.intent.as_str() {
+            "chat" | "none" => None,
+            name => Some((name, reply.slots.as_slice())),
+        };
+        if answered || intent.is_some() {
+            if let Ok(mut sessions) = self.sessions.lock() {
+                let exchange =
+                    Some((self.query.as_str(), reply.text.as_str())).filter(|_| answered);
+                sessions.record(self.player_id, exchange, intent);
+            }
         }
+        self.response = Some(reply);
 
         Ok(())
     }
+
+    fn push(&self, amx: &Amx, allocator: &Allocator) -> AmxResult<()> {
+        let response = self.response.as_ref().ok_or(AmxError::NotFound)?;
+
+        amx.push(allocator.allot_string(&format_slots(&response.slots))?)?;
+        amx.push(allocator.allot_string(&response.intent)?)?;
+        amx.push(self.offset)?;
+        amx.push(allocator.allot_string(&response.text)?)?;
+        amx.push(self.player_id)
+    }
+
+    fn push_error(&self, amx: &Amx, allocator: &Allocator, error: &JobError) -> AmxResult<()> {
+        push_error(amx, allocator, self.player_id, error, self.offset)
+    }
 }
 
 impl super::Plugin {
@@ -103,8 +263,64 @@ impl super::Plugin {
         player_id: u32,
         query: AmxString,
         offset: u32,
+    ) -> AmxResult<u32> {
+        if !self.config.features.alexa {
+            warn!("Alexa: feature is disabled");
+            return Ok(0);
+        }
+
+        let job = AlexaJob {
+            engine: Arc::clone(&self.intents),
+            config: self.config.alexa.clone(),
+            sessions: Arc::clone(&self.alexa_sessions),
+            query: query.to_string(),
+            player_id,
+            offset,
+            response: None,
+        };
+
+        match self.alexa.add_job(&self.pool, amx, job) {
+            Ok(id) => Ok(id),
+            Err(_e) => {
+                warn!("Alexa: {}", _e);
+                Ok(0)
+            }
+        }
+    }
+
+    /// Reads one slot out of the `slots[]` string passed to `OnAlexaReply`.
+    #[native(name = "AlexaGetSlot")]
+    pub fn native_alexa_get_slot(
+        &mut self,
+        _amx: &Amx,
+        slots: AmxString,
+        name: AmxString,
+        response: UnsizedBuffer,
+        size: usize,
     ) -> AmxResult<bool> {
-        AlexaPlugin::add_job(&mut self.alexa, amx, player_id, query, offset);
-        Ok(true)
+        match find_slot(&slots.to_string(), &name.to_string()) {
+            Some(value) => {
+                let mut buffer = response.into_sized_buffer(size);
+                let _ = samp::cell::string::put_in_buffer(&mut buffer, &value);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    #[native(name = "AlexaResetSession")]
+    pub fn native_alexa_reset_session(&mut self, _amx: &Amx, player_id: u32) -> AmxResult<bool> {
+        match self.alexa_sessions.lock() {
+            Ok(mut sessions) => Ok(sessions.reset(player_id)),
+            Err(_) => Ok(false),
+        }
+    }
+
+    #[native(name = "AlexaResetAllSessions")]
+    pub fn native_alexa_reset_all_sessions(&mut self, _amx: &Amx) -> AmxResult<usize> {
+        match self.alexa_sessions.lock() {
+            Ok(mut sessions) => Ok(sessions.reset_all()),
+            Err(_) => Ok(0),
+        }
     }
 }
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: &str, content: &str) -> Self {
+        ChatMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+}
+
+/// What a backend gets to know about a player's earlier queries.
+#[derive(Clone, Default)]
+pub struct SessionContext {
+    pub messages: Vec<ChatMessage>,
+    /// Last intent that matched, follow-up intents can build on it.
+    pub last_intent: Option<String>,
+    pub slots: Vec<(String, String)>,
+}
+
+struct Session {
+    context: SessionContext,
+    last_used: Instant,
+}
+
+/// Per-player conversation state, sessions idle longer than the expiry start over.
+pub struct SessionStore {
+    sessions: HashMap<u32, Session>,
+    history_length: usize,
+    expiry: Duration,
+}
+
+impl SessionStore {
+    pub fn new(history_length: usize, expiry_secs: u64) -> Self {
+        SessionStore {
+            sessions: HashMap::new(),
+            history_length,
+            expiry: Duration::from_secs(expiry_secs),
+        }
+    }
+
+    pub fn configure(&mut self, history_length: usize, expiry_secs: u64) {
+        self.history_length = history_length;
+        self.expiry = Duration::from_secs(expiry_secs);
+    }
+
+    fn purge_expired(&mut self) {
+        let expiry = self.expiry;
+        self.sessions
+            .retain(|_, session| session.last_used.elapsed() < expiry);
+    }
+
+    pub fn context(&mut self, player_id: u32) -> SessionContext {
+        self.purge_expired();
+        self.sessions
+            .get(&player_id)
+            .map(|session| session.context.clone())
+            .unwrap_or_default()
+    }
+
+    /// Stores one `(query, reply)` exchange and the intent that matched, either may be `None`.
+    pub fn record(
+        &mut self,
+        player_id: u32,
+        exchange: Option<(&str, &str)>,
+        intent: Option<(&str, &[(String, String)])>,
+    ) {
+        self.purge_expired();
+
+        let history_length = self.history_length;
+        let session = self.sessions.entry(player_id).or_insert_with(|| Session {
+            context: SessionContext::default(),
+            last_used: Instant::now(),
+        });
+        session.last_used = Instant::now();
+
+        let context = &mut session.context;
+        if let Some((query, reply)) = exchange {
+            context.messages.push(ChatMessage::new("user", query));
+            context.messages.push(ChatMessage::new("assistant", reply));
+
+            // Whole exchanges only, so the history always starts with the player's message
+            let keep = history_length - history_length % 2;
+            let excess = context.messages.len().saturating_sub(keep);
+            context.messages.drain(..excess);
+        }
+
+        if let Some((name, slots)) = intent {
+            context.last_intent = Some(name.to_string());
+            context.slots = slots.to_vec();
+        }
+    }
+
+    pub fn reset(&mut self, player_id: u32) -> bool {
+        self.sessions.remove(&player_id).is_some()
+    }
+
+    pub fn reset_all(&mut self) -> usize {
+        let count = self.sessions.len();
+        self.sessions.clear();
+        count
+    }
+}
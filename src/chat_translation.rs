@@ -0,0 +1,160 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+use samp::amx::{Allocator, Amx};
+use samp::cell::{AmxString, UnsizedBuffer};
+use samp::error::{AmxError, AmxResult};
+use samp::native;
+
+use crate::jobs::{push_error, Job, JobError};
+use crate::translation::{translate, TranslateConfig, Translation, TranslationCache};
+
+/// One chat line translated into one recipient language.
+pub struct ChatTranslateJob {
+    config: TranslateConfig,
+    cache: Arc<Mutex<TranslationCache>>,
+    sender_id: u32,
+    text: String,
+    language: String,
+    offset: u32,
+    response: Option<Translation>,
+}
+
+impl Job for ChatTranslateJob {
+    const CALLBACK: &'static str = "OnChatTranslated";
+    const ERROR_CALLBACK: &'static str = "OnChatTranslateError";
+    const KIND: &'static str = "translate";
+
+    fn player_id(&self) -> Option<u32> {
+        Some(self.sender_id)
+    }
+
+    fn executor(&mut self) -> Result<(), JobError> {
+        let translation = translate(&self.config, &self.text, &self.language, false)?;
+
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.insert(
+                &self.config,
+                &self.text,
+                &self.language,
+                translation.clone(),
+            );
+        }
+        self.response = Some(translation);
+
+        Ok(())
+    }
+
+    fn push(&self, amx: &Amx, allocator: &Allocator) -> AmxResult<()> {
+        let response = self.response.as_ref().ok_or(AmxError::NotFound)?;
+
+        amx.push(self.offset)?;
+        amx.push(allocator.allot_string(&response.text)?)?;
+        amx.push(allocator.allot_string(&self.language)?)?;
+        amx.push(self.sender_id)
+    }
+
+    fn push_error(&self, amx: &Amx, allocator: &Allocator, error: &JobError) -> AmxResult<()> {
+        push_error(amx, allocator, self.sender_id, error, self.offset)
+    }
+}
+
+impl super::Plugin {
+    #[native(name = "SetPlayerLanguage")]
+    pub fn native_set_player_language(
+        &mut self,
+        _amx: &Amx,
+        player_id: u32,
+        language: AmxString,
+    ) -> AmxResult<bool> {
+        let language = language.to_string().trim().to_lowercase();
+        if language.is_empty() {
+            self.player_languages.remove(&player_id);
+        } else {
+            self.player_languages.insert(player_id, language);
+        }
+        Ok(true)
+    }
+
+    #[native(name = "ResetPlayerLanguage")]
+    pub fn native_reset_player_language(&mut self, _amx: &Amx, player_id: u32) -> AmxResult<bool> {
+        Ok(self.player_languages.remove(&player_id).is_some())
+    }
+
+    #[native(name = "GetPlayerLanguage")]
+    pub fn native_get_player_language(
+        &mut self,
+        _amx: &Amx,
+        player_id: u32,
+        response: UnsizedBuffer,
+        size: usize,
+    ) -> AmxResult<bool> {
+        let language = match self.player_languages.get(&player_id) {
+            Some(language) => language,
+            None => return Ok(false),
+        };
+        let mut buffer = response.into_sized_buffer(size);
+        let _ = samp::cell::string::put_in_buffer(&mut buffer, language);
+        Ok(true)
+    }
+
+    /// Queues one translation per language spoken by the other registered players
+    /// and returns how many were queued.
+    #[native(name = "TranslateChat")]
+    pub fn native_translate_chat(
+        &mut self,
+        amx: &Amx,
+        sender_id: u32,
+        text: AmxString,
+        offset: u32,
+    ) -> AmxResult<u32> {
+        if !self.config.features.translate {
+            warn!("TranslateChat: feature is disabled");
+            return Ok(0);
+        }
+
+        let text = text.to_string();
+        let sender_language = self.player_languages.get(&sender_id);
+        let languages: BTreeSet<String> = self
+            .player_languages
+            .iter()
+            .filter(|(player_id, _)| **player_id != sender_id)
+            .map(|(_, language)| language)
+            .filter(|language| Some(*language) != sender_language)
+            .cloned()
+            .collect();
+
+        let mut queued = 0;
+        for language in languages {
+            let cached = self
+                .translation_cache
+                .lock()
+                .ok()
+                .and_then(|mut cache| cache.get(&text, &language));
+
+            let job = ChatTranslateJob {
+                config: self.config.translate.clone(),
+                cache: Arc::clone(&self.translation_cache),
+                sender_id,
+                text: text.clone(),
+                language,
+                offset,
+                response: cached,
+            };
+
+            if job.response.is_some() {
+                self.chat_translate.add_completed(amx, job);
+                queued += 1;
+                continue;
+            }
+
+            match self.chat_translate.add_job(&self.pool, amx, job) {
+                Ok(_) => queued += 1,
+                Err(_e) => warn!("TranslateChat: {}", _e),
+            }
+        }
+
+        Ok(queued)
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use log::{error, info, warn};
+use samp::amx::Amx;
+use samp::cell::{AmxString, UnsizedBuffer};
+use samp::error::AmxResult;
+use samp::native;
+
+/// IPv4 addresses live in the IPv4-mapped IPv6 range so both families share one number line.
+pub fn parse_ip(text: &str) -> Option<u128> {
+    match text.trim().parse::<IpAddr>().ok()? {
+        IpAddr::V4(v4) => Some(u128::from(v4.to_ipv6_mapped())),
+        IpAddr::V6(v6) => Some(u128::from(v6)),
+    }
+}
+
+fn mask(prefix: u32) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        u128::MAX << (128 - prefix)
+    }
+}
+
+/// A network like `10.0.0.0/8` or `2001:db8::/32`, a bare address is a single host.
+/// IPv4-mapped IPv6 ranges such as `::ffff:1.2.3.0/120` parse as their IPv4 form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Cidr {
+    network: u128,
+    /// Prefix length in the shared 128 bit space, IPv4 prefixes are offset by 96.
+    prefix: u32,
+    v4: bool,
+}
+
+impl Cidr {
+    pub fn parse(text: &str) -> Option<Cidr> {
+        let mut parts = text.trim().splitn(2, '/');
+        let address: IpAddr = parts.next()?.trim().parse().ok()?;
+        let length = match parts.next() {
+            Some(length) => Some(length.trim().parse::<u32>().ok()?),
+            None => None,
+        };
+
+        let (value, prefix, v4) = match address {
+            IpAddr::V4(v4) => {
+                let length = length.unwrap_or(32);
+                if length > 32 {
+                    return None;
+                }
+                (u128::from(v4.to_ipv6_mapped()), length + 96, true)
+            }
+            IpAddr::V6(v6) => {
+                let length = length.unwrap_or(128);
+                if length > 128 {
+                    return None;
+                }
+                let mapped = length >= 96 && v6.to_ipv4_mapped().is_some();
+                (u128::from(v6), length, mapped)
+            }
+        };
+
+        Some(Cidr {
+            network: value & mask(prefix),
+            prefix,
+            v4,
+        })
+    }
+
+    pub fn first(&self) -> u128 {
+        self.network
+    }
+
+    pub fn last(&self) -> u128 {
+        self.network | !mask(self.prefix)
+    }
+
+    pub fn contains(&self, ip: u128) -> bool {
+        ip >= self.first() && ip <= self.last()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.v4 {
+            let address = Ipv4Addr::from(self.network as u32);
+            write!(f, "{}/{}", address, self.prefix - 96)
+        } else {
+            write!(f, "{}/{}", Ipv6Addr::from(self.network), self.prefix)
+        }
+    }
+}
+
+/// Banned ranges, merged into sorted non-overlapping intervals for binary search.
+pub struct BanList {
+    entries: BTreeSet<Cidr>,
+    merged: Vec<(u128, u128)>,
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        BanList {
+            entries: BTreeSet::new(),
+            merged: Vec::new(),
+        }
+    }
+
+    fn rebuild(&mut self) {
+        self.merged.clear();
+
+        // Entries are ordered by network, so each range can only extend the last one
+        for cidr in &self.entries {
+            match self.merged.last_mut() {
+                Some((_, end)) if cidr.first() <= end.saturating_add(1) => {
+                    *end = (*end).max(cidr.last());
+                }
+                _ => self.merged.push((cidr.first(), cidr.last())),
+            }
+        }
+    }
+
+    pub fn add(&mut self, cidr: Cidr) -> bool {
+        let added = self.entries.insert(cidr);
+        if added {
+            self.rebuild();
+        }
+        added
+    }
+
+    pub fn remove(&mut self, cidr: &Cidr) -> bool {
+        let removed = self.entries.remove(cidr);
+        if removed {
+            self.rebuild();
+        }
+        removed
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.merged.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn contains(&self, ip: u128) -> bool {
+        let after = self.merged.partition_point(|(start, _)| *start <= ip);
+        after > 0 && self.merged[after - 1].1 >= ip
+    }
+
+    /// Adds one range per line, `#` starts a comment. Returns how many were new.
+    pub fn load(&mut self, path: &str) -> io::Result<usize> {
+        let content = fs::read_to_string(path)?;
+        let mut added = 0;
+
+        for line in content.lines() {
+            let entry = line.split('#').next().unwrap_or("").trim();
+            if entry.is_empty() {
+                continue;
+            }
+
+            match Cidr::parse(entry) {
+                Some(cidr) => {
+                    if self.entries.insert(cidr) {
+                        added += 1;
+                    }
+                }
+                None => warn!("{}: ignoring \"{}\"", path, entry),
+            }
+        }
+
+        self.rebuild();
+        Ok(added)
+    }
+}
+
+impl super::Plugin {
+    #[native(name = "IpIsValid")]
+    pub fn native_ip_is_valid(&mut self, _amx: &Amx, ip: AmxString) -> AmxResult<bool> {
+        Ok(parse_ip(&ip.to_string()).is_some())
+    }
+
+    #[native(name = "IpIsV6")]
+    pub fn native_ip_is_v6(&mut self, _amx: &Amx, ip: AmxString) -> AmxResult<bool> {
+        Ok(ip.to_string().trim().parse::<Ipv6Addr>().is_ok())
+    }
+
+    /// Writes the canonical form, e.g. `2001:db8::1` for `2001:0DB8:0:0:0:0:0:1`.
+    #[native(name = "IpNormalize")]
+    pub fn native_ip_normalize(
+        &mut self,
+        _amx: &Amx,
+        ip: AmxString,
+        dest: UnsizedBuffer,
+        size: usize,
+    ) -> AmxResult<bool> {
+        match ip.to_string().trim().parse::<IpAddr>() {
+            Ok(address) => {
+                let mut buffer = dest.into_sized_buffer(size);
+                let _ = samp::cell::string::put_in_buffer(&mut buffer, &address.to_string());
+                Ok(true)
+            }
+            Err(_) => Ok(false),
+        }
+    }
+
+    #[native(name = "IpInRange")]
+    pub fn native_ip_in_range(
+        &mut self,
+        _amx: &Amx,
+        ip: AmxString,
+        range: AmxString,
+    ) -> AmxResult<bool> {
+        match (parse_ip(&ip.to_string()), Cidr::parse(&range.to_string())) {
+            (Some(ip), Some(range)) => Ok(range.contains(ip)),
+            _ => Ok(false),
+        }
+    }
+
+    #[native(name = "RangeBanAdd")]
+    pub fn native_range_ban_add(&mut self, _amx: &Amx, range: AmxString) -> AmxResult<bool> {
+        let range = range.to_string();
+        match Cidr::parse(&range) {
+            Some(cidr) => Ok(self.range_bans.add(cidr)),
+            None => {
+                warn!("RangeBanAdd: \"{}\" is not a valid range", range);
+                Ok(false)
+            }
+        }
+    }
+
+    #[native(name = "RangeBanRemove")]
+    pub fn native_range_ban_remove(&mut self, _amx: &Amx, range: AmxString) -> AmxResult<bool> {
+        match Cidr::parse(&range.to_string()) {
+            Some(cidr) => Ok(self.range_bans.remove(&cidr)),
+            None => Ok(false),
+        }
+    }
+
+    #[native(name = "RangeBanCheck")]
+    pub fn native_range_ban_check(&mut self, _amx: &Amx, ip: AmxString) -> AmxResult<bool> {
+        match parse_ip(&ip.to_string()) {
+            Some(ip) => Ok(self.range_bans.contains(ip)),
+            None => Ok(false),
+        }
+    }
+
+    /// Returns how many new ranges were added, -1 when the file can't be read.
+    #[native(name = "RangeBanLoad")]
+    pub fn native_range_ban_load(&mut self, _amx: &Amx, path: AmxString) -> AmxResult<i32> {
+        let path = path.to_string();
+        match self.range_bans.load(&path) {
+            Ok(added) => {
+                info!("loaded {} banned ranges from {}", added, path);
+                Ok(added as i32)
+            }
+            Err(_e) => {
+                error!("unable to read {}: {}", path, _e);
+                Ok(-1)
+            }
+        }
+    }
+
+    #[native(name = "RangeBanCount")]
+    pub fn native_range_ban_count(&mut self, _amx: &Amx) -> AmxResult<usize> {
+        Ok(self.range_bans.len())
+    }
+
+    #[native(name = "RangeBanClear")]
+    pub fn native_range_ban_clear(&mut self, _amx: &Amx) -> AmxResult<bool> {
+        self.range_bans.clear();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(text: &str) -> Cidr {
+        Cidr::parse(text).unwrap_or_else(|| panic!("{} should parse", text))
+    }
+
+    fn ip(text: &str) -> u128 {
+        parse_ip(text).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(cidr("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("1.2.3.4").to_string(), "1.2.3.4/32");
+        assert_eq!(cidr("2001:db8::1/32").to_string(), "2001:db8::/32");
+        assert_eq!(cidr(" 0.0.0.0/0 ").to_string(), "0.0.0.0/0");
+        assert!(Cidr::parse("10.0.0.0/33").is_none());
+        assert!(Cidr::parse("::/129").is_none());
+        assert!(Cidr::parse("10.0.0/8").is_none());
+        assert!(Cidr::parse("10.0.0.0/x").is_none());
+    }
+
+    #[test]
+    fn mapped_ranges_equal_their_ipv4_form() {
+        assert_eq!(cidr("::ffff:1.2.3.0/120"), cidr("1.2.3.0/24"));
+        assert_eq!(cidr("::ffff:1.2.3.0/120").to_string(), "1.2.3.0/24");
+
+        let mut bans = BanList::new();
+        assert!(bans.add(cidr("1.2.3.0/24")));
+        assert!(!bans.add(cidr("::ffff:1.2.3.0/120")));
+        assert!(bans.remove(&cidr("::ffff:1.2.3.0/120")));
+        assert_eq!(bans.len(), 0);
+    }
+
+    #[test]
+    fn contains_at_edges() {
+        let range = cidr("192.168.1.0/24");
+        assert!(range.contains(ip("192.168.1.0")));
+        assert!(range.contains(ip("192.168.1.255")));
+        assert!(!range.contains(ip("192.168.0.255")));
+        assert!(!range.contains(ip("192.168.2.0")));
+
+        let mut bans = BanList::new();
+        bans.add(range);
+        bans.add(cidr("192.168.2.0/24"));
+        assert!(bans.contains(ip("192.168.1.255")));
+        assert!(bans.contains(ip("192.168.2.0")));
+        assert!(bans.contains(ip("192.168.2.255")));
+        assert!(!bans.contains(ip("192.168.3.0")));
+        assert!(!bans.contains(ip("192.168.0.255")));
+
+        assert!(cidr("::/0").contains(ip("1.2.3.4")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff")));
+    }
+
+    #[test]
+    fn merging_after_removal() {
+        let mut bans = BanList::new();
+        bans.add(cidr("10.0.0.0/8"));
+        bans.add(cidr("10.1.0.0/16"));
+        assert!(bans.contains(ip("10.200.0.1")));
+
+        assert!(bans.remove(&cidr("10.0.0.0/8")));
+        assert_eq!(bans.len(), 1);
+        assert!(bans.contains(ip("10.1.0.0")));
+        assert!(bans.contains(ip("10.1.255.255")));
+        assert!(!bans.contains(ip("10.0.255.255")));
+        assert!(!bans.contains(ip("10.2.0.0")));
+        assert!(!bans.contains(ip("10.200.0.1")));
+    }
+}
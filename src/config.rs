@@ -0,0 +1,236 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use log::LevelFilter;
+use samp::amx::Amx;
+use samp::error::AmxResult;
+use samp::native;
+
+use crate::alexa::AlexaConfig;
+use crate::http::{build_client, HttpClientConfig};
+use crate::ip_info::IpInfoConfig;
+use crate::ip_risk::IpRiskConfig;
+use crate::jobs::TickConfig;
+use crate::math::MathConfig;
+use crate::pool::PoolConfig;
+use crate::translation::TranslateConfig;
+
+/// Read from the server root, every section and key is optional.
+pub const CONFIG_FILE: &str = "iorp_core.toml";
+
+#[derive(Clone, serde_derive::Deserialize)]
+#[serde(default)]
+pub struct Endpoints {
+    pub ip_info: String,
+    pub ip_api: String,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Endpoints {
+            ip_info: "https://ipinfo.io/".to_string(),
+            ip_api: "http://ip-api.com/json/".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Default, serde_derive::Deserialize)]
+#[serde(default)]
+pub struct Tokens {
+    /// Used when `IpInfo` is called with an empty token.
+    pub ip_info: String,
+}
+
+#[derive(Clone, serde_derive::Deserialize)]
+#[serde(default)]
+pub struct Logging {
+    /// One of off, error, warn, info, debug, trace.
+    pub level: String,
+    pub prefix: String,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Logging {
+            level: "info".to_string(),
+            prefix: "Indian Ocean Roleplay".to_string(),
+        }
+    }
+}
+
+/// Disabled natives refuse new requests and return 0.
+#[derive(Clone, serde_derive::Deserialize)]
+#[serde(default)]
+pub struct Features {
+    pub alexa: bool,
+    pub math: bool,
+    pub ip_info: bool,
+    pub ip_risk: bool,
+    pub http: bool,
+    pub translate: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features {
+            alexa: true,
+            math: true,
+            ip_info: true,
+            ip_risk: true,
+            http: true,
+            translate: true,
+        }
+    }
+}
+
+#[derive(Clone, Default, serde_derive::Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub endpoints: Endpoints,
+    pub tokens: Tokens,
+    pub pool: PoolConfig,
+    pub tick: TickConfig,
+    pub http: HttpClientConfig,
+    pub translate: TranslateConfig,
+    pub alexa: AlexaConfig,
+    pub ip_info: IpInfoConfig,
+    pub ip_risk: IpRiskConfig,
+    pub math: MathConfig,
+    pub logging: Logging,
+    pub features: Features,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "unable to read {}: {}", CONFIG_FILE, e),
+            ConfigError::Parse(e) => write!(f, "unable to parse {}: {}", CONFIG_FILE, e),
+            ConfigError::Invalid(errors) => {
+                write!(f, "invalid {}: {}", CONFIG_FILE, errors.join("; "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Loads `CONFIG_FILE`, falling back to defaults when the file does not exist.
+    pub fn load() -> Result<Config, ConfigError> {
+        let content = match fs::read_to_string(CONFIG_FILE) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(ConfigError::Io(e)),
+        };
+
+        let config: Config = toml::from_str(&content).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+
+        if self.pool.workers == 0 {
+            errors.push("pool.workers must be at least 1".to_string());
+        }
+        if self.pool.queue_depth == 0 {
+            errors.push("pool.queue_depth must be at least 1".to_string());
+        }
+        if self.tick.max_callbacks == 0 {
+            errors.push("tick.max_callbacks must be at least 1".to_string());
+        }
+        // A zero budget is spent before the first callback, nothing would ever be delivered
+        if self.tick.max_millis == 0 {
+            errors.push("tick.max_millis must be at least 1".to_string());
+        }
+        if self.http.timeout_ms == 0 {
+            errors.push("http.timeout_ms must be at least 1".to_string());
+        }
+        if self.http.connect_timeout_ms == 0 {
+            errors.push("http.connect_timeout_ms must be at least 1".to_string());
+        }
+        // Catches a bad proxy here instead of failing later in apply_config
+        if let Err(e) = build_client(&self.http) {
+            errors.push(format!("http client can't be built: {}", e));
+        }
+        if self.math.decimals > 10 {
+            errors.push("math.decimals must be at most 10".to_string());
+        }
+        for (code, rate) in &self.math.currencies {
+            if !rate.is_finite() || *rate <= 0.0 {
+                errors.push(format!("math.currencies.{} must be above 0", code));
+            }
+        }
+        if LevelFilter::from_str(&self.logging.level).is_err() {
+            errors.push(format!(
+                "logging.level \"{}\" is unknown",
+                self.logging.level
+            ));
+        }
+
+        let endpoints = [
+            ("endpoints.ip_info", &self.endpoints.ip_info),
+            ("endpoints.ip_api", &self.endpoints.ip_api),
+            ("translate.endpoint", &self.translate.endpoint),
+            ("alexa.chat_endpoint", &self.alexa.chat_endpoint),
+        ];
+        for (key, url) in endpoints.iter() {
+            if let Err(e) = reqwest::Url::parse(url) {
+                errors.push(format!("{} \"{}\" is not a valid url: {}", key, url, e));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(errors))
+        }
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        LevelFilter::from_str(&self.logging.level).unwrap_or(LevelFilter::Info)
+    }
+}
+
+static LOG_PREFIX: RwLock<String> = RwLock::new(String::new());
+
+/// Prefix used by the log formatter, changed whenever a config is applied.
+pub fn log_prefix() -> String {
+    let prefix = LOG_PREFIX.read().unwrap();
+    if prefix.is_empty() {
+        Logging::default().prefix
+    } else {
+        prefix.clone()
+    }
+}
+
+pub fn set_log_prefix(prefix: &str) {
+    *LOG_PREFIX.write().unwrap() = prefix.to_string();
+}
+
+/// Joins an endpoint base and a path without doubling or dropping the slash.
+pub fn endpoint_url(base: &str, path: &str) -> String {
+    format!(
+        "{}/{}",
+        base.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    )
+}
+
+impl super::Plugin {
+    #[native(name = "ReloadCoreConfig")]
+    pub fn native_reload_core_config(&mut self, _amx: &Amx) -> AmxResult<bool> {
+        Ok(self.reload_config())
+    }
+}
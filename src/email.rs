@@ -1,23 +1,22 @@
-use log::info;
-use reqwest;
+use log::{info, warn};
 use samp::amx::Amx;
 use samp::cell::AmxString;
 use samp::error::AmxResult;
 use samp::native;
 
+use crate::http::client;
+
 pub fn send_post(url: &str, body: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let client = reqwest::blocking::Client::new();
-    client
+    client()
         .post(url)
         .header(reqwest::header::CONTENT_TYPE, "application/json")
-        .body(format!("{}", body))
+        .body(body.to_string())
         .send()?;
     Ok(())
 }
 
 pub fn send_get(url: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let client = reqwest::blocking::Client::new();
-    client
+    client()
         .get(url)
         .header(reqwest::header::CONTENT_TYPE, "application/json")
         .send()?;
@@ -27,14 +26,25 @@ pub fn send_get(url: &str) -> Result<(), Box<dyn std::error::Error>> {
 impl super::Plugin {
     #[native(name = "sendHttpGet")]
     pub fn native_send_http_get(&mut self, _amx: &Amx, url: AmxString) -> AmxResult<bool> {
+        if !self.config.features.http {
+            warn!("sendHttpGet: feature is disabled");
+            return Ok(false);
+        }
+
         let input_url = url.to_string();
-        std::thread::spawn(move || {
-            match send_get(&input_url) {
-                Err(_e) => info!("{}", _e),
-                Ok(_) => (),
-            };
+        let submitted = self.pool.submit("http", move || {
+            if let Err(_e) = send_get(&input_url) {
+                info!("{}", _e);
+            }
         });
-        Ok(true)
+
+        match submitted {
+            Ok(_) => Ok(true),
+            Err(_e) => {
+                warn!("sendHttpGet: {}", _e);
+                Ok(false)
+            }
+        }
     }
 
     #[native(name = "sendHttpPost")]
@@ -44,14 +54,25 @@ impl super::Plugin {
         url: AmxString,
         body: AmxString,
     ) -> AmxResult<bool> {
+        if !self.config.features.http {
+            warn!("sendHttpPost: feature is disabled");
+            return Ok(false);
+        }
+
         let input_url = url.to_string();
         let input_body = body.to_string();
-        std::thread::spawn(move || {
-            match send_post(&input_url, &input_body) {
-                Err(_e) => info!("{}", _e),
-                Ok(_) => (),
-            };
+        let submitted = self.pool.submit("http", move || {
+            if let Err(_e) = send_post(&input_url, &input_body) {
+                info!("{}", _e);
+            }
         });
-        Ok(true)
+
+        match submitted {
+            Ok(_) => Ok(true),
+            Err(_e) => {
+                warn!("sendHttpPost: {}", _e);
+                Ok(false)
+            }
+        }
     }
 }
@@ -0,0 +1,684 @@
+use std::collections::HashMap;
+use std::f64::consts;
+use std::fmt;
+
+/// Longer input is refused, it also bounds how deep evaluation recurses.
+const MAX_INPUT_LENGTH: usize = 512;
+
+/// Parentheses, signs, powers and calls nested deeper than this are refused
+/// so a hostile string can't overflow the server thread's stack.
+const MAX_DEPTH: usize = 64;
+
+/// Error codes written to the `error` argument of `MathEval`, 0 means success.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExprErrorKind {
+    Syntax = 1,
+    UnknownName = 2,
+    DivisionByZero = 3,
+    Undefined = 4,
+    Overflow = 5,
+}
+
+/// Parse or evaluation failure, `position` is the 1-based character it was found at.
+#[derive(Debug, Clone)]
+pub struct ExprError {
+    pub kind: ExprErrorKind,
+    pub message: String,
+    pub position: Option<usize>,
+}
+
+impl ExprError {
+    fn at(position: usize, message: impl Into<String>) -> Self {
+        ExprError {
+            kind: ExprErrorKind::Syntax,
+            message: message.into(),
+            position: Some(position + 1),
+        }
+    }
+
+    pub fn eval(kind: ExprErrorKind, message: impl Into<String>) -> Self {
+        ExprError {
+            kind,
+            message: message.into(),
+            position: None,
+        }
+    }
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.position {
+            Some(position) => write!(f, "{} at position {}", self.message, position),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Operator(char),
+    Open,
+    Close,
+    Comma,
+}
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, ExprError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        if c.is_ascii_digit() || c == '.' {
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+
+            // 1e5 and 2.5E-3, `2exp(1)` is 2 * exp(1) but a dangling `2e` or `2e+` is an error
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                let mut exponent = i + 1;
+                let signed =
+                    exponent < chars.len() && (chars[exponent] == '+' || chars[exponent] == '-');
+                if signed {
+                    exponent += 1;
+                }
+
+                if exponent < chars.len() && chars[exponent].is_ascii_digit() {
+                    i = exponent;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                } else if signed
+                    || exponent >= chars.len()
+                    || !(chars[exponent].is_alphanumeric() || chars[exponent] == '_')
+                {
+                    let text: String = chars[start..exponent].iter().collect();
+                    return Err(ExprError::at(start, format!("invalid number \"{}\"", text)));
+                }
+            }
+
+            let text: String = chars[start..i].iter().collect();
+            let number = text
+                .parse::<f64>()
+                .map_err(|_| ExprError::at(start, format!("invalid number \"{}\"", text)))?;
+            tokens.push((start, Token::Number(number)));
+            continue;
+        }
+
+        if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let name: String = chars[start..i].iter().collect();
+            tokens.push((start, Token::Name(name.to_lowercase())));
+            continue;
+        }
+
+        let token = match c {
+            '+' | '-' | '*' | '/' | '%' | '^' => {
+                // `**` is accepted as power
+                if c == '*' && i + 1 < chars.len() && chars[i + 1] == '*' {
+                    i += 1;
+                    Token::Operator('^')
+                } else {
+                    Token::Operator(c)
+                }
+            }
+            '×' => Token::Operator('*'),
+            '÷' => Token::Operator('/'),
+            '(' => Token::Open,
+            ')' => Token::Close,
+            ',' => Token::Comma,
+            _ => return Err(ExprError::at(start, format!("unexpected '{}'", c))),
+        };
+        tokens.push((start, token));
+        i += 1;
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Function {
+    Sqrt,
+    Cbrt,
+    Abs,
+    Sin,
+    Cos,
+    Tan,
+    Asin,
+    Acos,
+    Atan,
+    Atan2,
+    Ln,
+    Log,
+    Log2,
+    Exp,
+    Floor,
+    Ceil,
+    Round,
+    Min,
+    Max,
+    Pow,
+    Hypot,
+}
+
+impl Function {
+    fn lookup(name: &str) -> Option<Function> {
+        let function = match name {
+            "sqrt" => Function::Sqrt,
+            "cbrt" => Function::Cbrt,
+            "abs" => Function::Abs,
+            "sin" => Function::Sin,
+            "cos" => Function::Cos,
+            "tan" => Function::Tan,
+            "asin" => Function::Asin,
+            "acos" => Function::Acos,
+            "atan" => Function::Atan,
+            "atan2" => Function::Atan2,
+            "ln" => Function::Ln,
+            "log" | "log10" => Function::Log,
+            "log2" => Function::Log2,
+            "exp" => Function::Exp,
+            "floor" => Function::Floor,
+            "ceil" => Function::Ceil,
+            "round" => Function::Round,
+            "min" => Function::Min,
+            "max" => Function::Max,
+            "pow" => Function::Pow,
+            "hypot" => Function::Hypot,
+            _ => return None,
+        };
+        Some(function)
+    }
+
+    /// Smallest and largest number of arguments, `None` means unbounded.
+    fn arity(self) -> (usize, Option<usize>) {
+        match self {
+            Function::Min | Function::Max => (1, None),
+            Function::Atan2 | Function::Pow | Function::Hypot => (2, Some(2)),
+            // log(x) is base 10, log(x, base) any base
+            Function::Log => (1, Some(2)),
+            _ => (1, Some(1)),
+        }
+    }
+
+    fn apply(self, args: &[f64]) -> f64 {
+        let x = args[0];
+        match self {
+            Function::Sqrt => x.sqrt(),
+            Function::Cbrt => x.cbrt(),
+            Function::Abs => x.abs(),
+            Function::Sin => x.sin(),
+            Function::Cos => x.cos(),
+            Function::Tan => x.tan(),
+            Function::Asin => x.asin(),
+            Function::Acos => x.acos(),
+            Function::Atan => x.atan(),
+            Function::Atan2 => x.atan2(args[1]),
+            Function::Ln => x.ln(),
+            Function::Log => match args.get(1) {
+                Some(base) => x.log(*base),
+                None => x.log10(),
+            },
+            Function::Log2 => x.log2(),
+            Function::Exp => x.exp(),
+            Function::Floor => x.floor(),
+            Function::Ceil => x.ceil(),
+            Function::Round => x.round(),
+            Function::Min => args.iter().cloned().fold(f64::INFINITY, f64::min),
+            Function::Max => args.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+            Function::Pow => x.powf(args[1]),
+            Function::Hypot => x.hypot(args[1]),
+        }
+    }
+}
+
+fn constant(name: &str) -> Option<f64> {
+    match name {
+        "pi" => Some(consts::PI),
+        "tau" => Some(consts::PI * 2.0),
+        "e" => Some(consts::E),
+        _ => None,
+    }
+}
+
+/// A parsed expression, evaluate it as often as needed.
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Number(f64),
+    Variable(String),
+    Negate(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    index: usize,
+    end: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|(_, token)| token)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.index)
+            .map(|(position, _)| *position)
+            .unwrap_or(self.end)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.index).map(|(_, token)| token.clone());
+        self.index += 1;
+        token
+    }
+
+    fn expect_close(&mut self) -> Result<(), ExprError> {
+        let position = self.position();
+        match self.next() {
+            Some(Token::Close) => Ok(()),
+            _ => Err(ExprError::at(position, "missing ')'")),
+        }
+    }
+
+    // expression := term (('+' | '-') term)*
+    fn expression(&mut self) -> Result<Expr, ExprError> {
+        let mut left = self.term()?;
+        while let Some(Token::Operator(operator)) = self.peek() {
+            let operator = *operator;
+            if operator != '+' && operator != '-' {
+                break;
+            }
+            self.index += 1;
+            let right = self.term()?;
+            left = Expr::Binary(operator, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    // term := unary (('*' | '/' | '%') unary)*, `2pi` and `3(1 + 2)` multiply implicitly
+    fn term(&mut self) -> Result<Expr, ExprError> {
+        let mut left = self.unary()?;
+        loop {
+            let operator = match self.peek() {
+                Some(Token::Operator(operator)) if "*/%".contains(*operator) => {
+                    let operator = *operator;
+                    self.index += 1;
+                    operator
+                }
+                Some(Token::Name(_)) | Some(Token::Open) => '*',
+                _ => break,
+            };
+            let right = self.unary()?;
+            left = Expr::Binary(operator, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    // Every recursive rule goes through here, so this is where nesting is counted
+    fn unary(&mut self) -> Result<Expr, ExprError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(ExprError::at(
+                self.position(),
+                "expression is nested too deeply",
+            ));
+        }
+
+        self.depth += 1;
+        let result = self.sign();
+        self.depth -= 1;
+        result
+    }
+
+    // unary := ('-' | '+') unary | power
+    fn sign(&mut self) -> Result<Expr, ExprError> {
+        match self.peek() {
+            Some(Token::Operator('-')) => {
+                self.index += 1;
+                Ok(Expr::Negate(Box::new(self.unary()?)))
+            }
+            Some(Token::Operator('+')) => {
+                self.index += 1;
+                self.unary()
+            }
+            _ => self.power(),
+        }
+    }
+
+    // power := primary ('^' unary)?, right associative so 2^3^2 is 2^9
+    fn power(&mut self) -> Result<Expr, ExprError> {
+        let base = self.primary()?;
+        if let Some(Token::Operator('^')) = self.peek() {
+            self.index += 1;
+            let exponent = self.unary()?;
+            return Ok(Expr::Binary('^', Box::new(base), Box::new(exponent)));
+        }
+        Ok(base)
+    }
+
+    fn primary(&mut self) -> Result<Expr, ExprError> {
+        let position = self.position();
+        match self.next() {
+            Some(Token::Number(number)) => Ok(Expr::Number(number)),
+            Some(Token::Open) => {
+                let inner = self.expression()?;
+                self.expect_close()?;
+                Ok(inner)
+            }
+            Some(Token::Name(name)) => {
+                if let Some(function) = Function::lookup(&name) {
+                    return self.call(position, &name, function);
+                }
+                match constant(&name) {
+                    Some(value) => Ok(Expr::Number(value)),
+                    None => Ok(Expr::Variable(name)),
+                }
+            }
+            Some(Token::Operator(operator)) => Err(ExprError::at(
+                position,
+                format!("expected a number before '{}'", operator),
+            )),
+            Some(Token::Close) => Err(ExprError::at(position, "unexpected ')'")),
+            Some(Token::Comma) => Err(ExprError::at(position, "unexpected ','")),
+            None => Err(ExprError::at(position, "unexpected end of expression")),
+        }
+    }
+
+    fn call(&mut self, position: usize, name: &str, function: Function) -> Result<Expr, ExprError> {
+        match self.next() {
+            Some(Token::Open) => {}
+            _ => {
+                return Err(ExprError::at(
+                    position,
+                    format!("{} needs its arguments in parentheses", name),
+                ))
+            }
+        }
+
+        let mut args = Vec::new();
+        if self.peek() != Some(&Token::Close) {
+            args.push(self.expression()?);
+            while self.peek() == Some(&Token::Comma) {
+                self.index += 1;
+                args.push(self.expression()?);
+            }
+        }
+        self.expect_close()?;
+
+        let (min, max) = function.arity();
+        if args.len() < min || max.is_some_and(|max| args.len() > max) {
+            let expected = match max {
+                Some(max) if max == min => format!("{}", min),
+                Some(max) => format!("{} to {}", min, max),
+                None => format!("at least {}", min),
+            };
+            return Err(ExprError::at(
+                position,
+                format!(
+                    "{} takes {} argument(s), got {}",
+                    name,
+                    expected,
+                    args.len()
+                ),
+            ));
+        }
+
+        Ok(Expr::Call(function, args))
+    }
+}
+
+impl Expr {
+    pub fn parse(input: &str) -> Result<Expr, ExprError> {
+        if input.chars().count() > MAX_INPUT_LENGTH {
+            return Err(ExprError::eval(
+                ExprErrorKind::Syntax,
+                format!("expression is longer than {} characters", MAX_INPUT_LENGTH),
+            ));
+        }
+
+        let tokens = tokenize(input)?;
+        if tokens.is_empty() {
+            return Err(ExprError::eval(ExprErrorKind::Syntax, "empty expression"));
+        }
+
+        let mut parser = Parser {
+            tokens,
+            index: 0,
+            end: input.chars().count(),
+            depth: 0,
+        };
+        let expr = parser.expression()?;
+
+        if parser.index < parser.tokens.len() {
+            let position = parser.position();
+            return Err(match parser.peek() {
+                Some(Token::Close) => ExprError::at(position, "unmatched ')'"),
+                _ => ExprError::at(position, "expected an operator"),
+            });
+        }
+
+        Ok(expr)
+    }
+
+    /// Evaluates with the given variables, undefined and infinite results are errors.
+    pub fn eval(&self, variables: &HashMap<String, f64>) -> Result<f64, ExprError> {
+        let value = self.eval_inner(variables)?;
+        if value.is_nan() {
+            return Err(ExprError::eval(
+                ExprErrorKind::Undefined,
+                "result is undefined",
+            ));
+        }
+        if value.is_infinite() {
+            return Err(ExprError::eval(
+                ExprErrorKind::Overflow,
+                "result is too large",
+            ));
+        }
+        Ok(value)
+    }
+
+    fn eval_inner(&self, variables: &HashMap<String, f64>) -> Result<f64, ExprError> {
+        match self {
+            Expr::Number(number) => Ok(*number),
+            Expr::Variable(name) => variables.get(name).cloned().ok_or_else(|| {
+                ExprError::eval(
+                    ExprErrorKind::UnknownName,
+                    format!("unknown name \"{}\"", name),
+                )
+            }),
+            Expr::Negate(inner) => Ok(-inner.eval_inner(variables)?),
+            Expr::Binary(operator, left, right) => {
+                let left = left.eval_inner(variables)?;
+                let right = right.eval_inner(variables)?;
+                match operator {
+                    '+' => Ok(left + right),
+                    '-' => Ok(left - right),
+                    '*' => Ok(left * right),
+                    '/' if right == 0.0 => Err(ExprError::eval(
+                        ExprErrorKind::DivisionByZero,
+                        "division by zero",
+                    )),
+                    '/' => Ok(left / right),
+                    '%' if right == 0.0 => Err(ExprError::eval(
+                        ExprErrorKind::DivisionByZero,
+                        "modulo by zero",
+                    )),
+                    '%' => Ok(left % right),
+                    _ => Ok(left.powf(right)),
+                }
+            }
+            Expr::Call(function, args) => {
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    values.push(arg.eval_inner(variables)?);
+                }
+                Ok(function.apply(&values))
+            }
+        }
+    }
+}
+
+/// Names usable with `MathSetVar`, functions and constants can't be shadowed.
+pub fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    let starts_well = match chars.next() {
+        Some(c) => c.is_alphabetic() || c == '_',
+        None => false,
+    };
+
+    starts_well
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+        && Function::lookup(name).is_none()
+        && constant(name).is_none()
+}
+
+/// Parses and evaluates in one go, without variables.
+pub fn evaluate(input: &str) -> Result<f64, ExprError> {
+    Expr::parse(input)?.eval(&HashMap::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(input: &str) -> f64 {
+        evaluate(input).unwrap_or_else(|e| panic!("{}: {}", input, e))
+    }
+
+    fn error(input: &str) -> ExprError {
+        match evaluate(input) {
+            Ok(value) => panic!("{} evaluated to {}", input, value),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(value("2 + 3 * 4"), 14.0);
+        assert_eq!(value("(2 + 3) * 4"), 20.0);
+        assert_eq!(value("10 - 4 - 3"), 3.0);
+        assert_eq!(value("7 % 4 * 2"), 6.0);
+    }
+
+    #[test]
+    fn power_is_right_associative_and_binds_tighter_than_sign() {
+        assert_eq!(value("2^3^2"), 512.0);
+        assert_eq!(value("2**3"), 8.0);
+        assert_eq!(value("-2^2"), -4.0);
+        assert_eq!(value("2^-1"), 0.5);
+    }
+
+    #[test]
+    fn implicit_multiplication() {
+        assert_eq!(value("2pi"), 2.0 * consts::PI);
+        assert_eq!(value("2(3)(4)"), 24.0);
+        assert_eq!(value("2 e"), 2.0 * consts::E);
+        assert_eq!(value("2exp(0)"), 2.0);
+    }
+
+    #[test]
+    fn exponent_notation() {
+        assert_eq!(value("1e3"), 1000.0);
+        assert_eq!(value("2.5E-1"), 0.25);
+        assert_eq!(error("2e").kind, ExprErrorKind::Syntax);
+        assert_eq!(error("2e+").kind, ExprErrorKind::Syntax);
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(value("sqrt(16)"), 4.0);
+        assert_eq!(value("log(1000)"), 3.0);
+        assert_eq!(value("log(8, 2)"), 3.0);
+        assert_eq!(value("max(1, 5, 3)"), 5.0);
+    }
+
+    #[test]
+    fn arity_errors() {
+        assert_eq!(
+            error("pow(2)").to_string(),
+            "pow takes 2 argument(s), got 1 at position 1"
+        );
+        assert_eq!(
+            error("1 + log(1, 2, 3)").to_string(),
+            "log takes 1 to 2 argument(s), got 3 at position 5"
+        );
+        assert_eq!(
+            error("min()").to_string(),
+            "min takes at least 1 argument(s), got 0 at position 1"
+        );
+    }
+
+    #[test]
+    fn error_positions() {
+        assert_eq!(error("(1 + 2").to_string(), "missing ')' at position 7");
+        assert_eq!(
+            error("1 + ").to_string(),
+            "unexpected end of expression at position 5"
+        );
+        assert_eq!(
+            error("1 2").to_string(),
+            "expected an operator at position 3"
+        );
+        assert_eq!(error("1 + 2)").to_string(), "unmatched ')' at position 6");
+        assert_eq!(error("3 $ 4").to_string(), "unexpected '$' at position 3");
+    }
+
+    #[test]
+    fn evaluation_errors() {
+        assert_eq!(error("1 / 0").kind, ExprErrorKind::DivisionByZero);
+        assert_eq!(error("5 % 0").kind, ExprErrorKind::DivisionByZero);
+        assert_eq!(error("sqrt(-1)").kind, ExprErrorKind::Undefined);
+        assert_eq!(error("10^400").kind, ExprErrorKind::Overflow);
+        assert_eq!(error("price * 2").kind, ExprErrorKind::UnknownName);
+    }
+
+    #[test]
+    fn variables() {
+        let expr = Expr::parse("price * 2").unwrap();
+        let mut variables = HashMap::new();
+        variables.insert("price".to_string(), 250.0);
+        assert_eq!(expr.eval(&variables).unwrap(), 500.0);
+    }
+
+    #[test]
+    fn deep_nesting_is_refused() {
+        let parentheses = format!("{}1{}", "(".repeat(200), ")".repeat(200));
+        assert_eq!(error(&parentheses).kind, ExprErrorKind::Syntax);
+
+        let signs = format!("{}1", "-".repeat(200));
+        assert_eq!(error(&signs).kind, ExprErrorKind::Syntax);
+
+        let nested = format!(
+            "{}1{}",
+            "(".repeat(MAX_DEPTH - 1),
+            ")".repeat(MAX_DEPTH - 1)
+        );
+        assert_eq!(value(&nested), 1.0);
+    }
+
+    #[test]
+    fn long_input_is_refused() {
+        let huge = format!("{}1{}", "(".repeat(10000), ")".repeat(10000));
+        assert_eq!(error(&huge).kind, ExprErrorKind::Syntax);
+
+        let sum = vec!["1"; MAX_INPUT_LENGTH].join("+");
+        assert_eq!(error(&sum).kind, ExprErrorKind::Syntax);
+    }
+}
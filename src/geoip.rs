@@ -0,0 +1,164 @@
+use std::fs;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use log::{error, info};
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+
+use crate::jobs::{ErrorCode, JobError};
+
+impl From<MaxMindDBError> for JobError {
+    fn from(error: MaxMindDBError) -> Self {
+        let code = match error {
+            MaxMindDBError::AddressNotFoundError(_) => ErrorCode::NotFound,
+            MaxMindDBError::IoError(_) => ErrorCode::Unknown,
+            _ => ErrorCode::Parse,
+        };
+
+        JobError::new(code, error.to_string())
+    }
+}
+
+/// One `.mmdb` file, reopened when its modification time changes.
+struct Database {
+    path: String,
+    modified: Option<SystemTime>,
+    reader: Option<Arc<Reader<Vec<u8>>>>,
+}
+
+impl Database {
+    fn new(path: &str) -> Self {
+        Database {
+            path: path.to_string(),
+            modified: None,
+            reader: None,
+        }
+    }
+
+    /// Current reader, a failed reload keeps serving the previous file.
+    fn reader(&mut self) -> Option<Arc<Reader<Vec<u8>>>> {
+        if self.path.is_empty() {
+            return None;
+        }
+
+        let modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+
+        if modified.is_some() && modified != self.modified {
+            match Reader::open_readfile(&self.path) {
+                Ok(reader) => {
+                    info!("loaded geoip database {}", self.path);
+                    self.reader = Some(Arc::new(reader));
+                    self.modified = modified;
+                }
+                Err(_e) => {
+                    error!("unable to open geoip database {}: {}", self.path, _e);
+                    // Don't retry until the file changes again
+                    self.modified = modified;
+                }
+            }
+        }
+
+        self.reader.clone()
+    }
+}
+
+/// Fields read from the GeoLite2 City and ASN databases, in ipinfo.io's format.
+pub struct GeoRecord {
+    pub city: Option<String>,
+    pub region: Option<String>,
+    pub country: Option<String>,
+    pub loc: Option<String>,
+    pub org: Option<String>,
+    pub postal: Option<String>,
+    pub timezone: Option<String>,
+}
+
+/// GeoLite2 City and ASN databases shared by the workers.
+pub struct GeoIp {
+    city: Mutex<Database>,
+    asn: Mutex<Database>,
+}
+
+impl GeoIp {
+    /// Files are opened on the first lookup, an empty path disables that database.
+    pub fn new(city_path: &str, asn_path: &str) -> Self {
+        GeoIp {
+            city: Mutex::new(Database::new(city_path)),
+            asn: Mutex::new(Database::new(asn_path)),
+        }
+    }
+
+    pub fn lookup(&self, ip: &str) -> Result<GeoRecord, JobError> {
+        let address: IpAddr = ip.trim().parse().map_err(|_| {
+            JobError::new(ErrorCode::InvalidInput, format!("invalid ip \"{}\"", ip))
+        })?;
+
+        let city_reader = self.city.lock().ok().and_then(|mut city| city.reader());
+        let asn_reader = self.asn.lock().ok().and_then(|mut asn| asn.reader());
+        if city_reader.is_none() && asn_reader.is_none() {
+            return Err(JobError::new(
+                ErrorCode::Unknown,
+                "no geoip database is loaded",
+            ));
+        }
+
+        let mut record = GeoRecord {
+            city: None,
+            region: None,
+            country: None,
+            loc: None,
+            org: None,
+            postal: None,
+            timezone: None,
+        };
+
+        if let Some(reader) = &city_reader {
+            let city: geoip2::City = reader.lookup(address)?;
+            record.city = city.city.and_then(|city| english_name(city.names));
+            record.region = city
+                .subdivisions
+                .and_then(|subdivisions| subdivisions.into_iter().next())
+                .and_then(|subdivision| english_name(subdivision.names));
+            record.country = city
+                .country
+                .and_then(|country| country.iso_code)
+                .map(str::to_string);
+            record.postal = city
+                .postal
+                .and_then(|postal| postal.code)
+                .map(str::to_string);
+
+            if let Some(location) = city.location {
+                if let (Some(latitude), Some(longitude)) = (location.latitude, location.longitude) {
+                    record.loc = Some(format!("{:.4},{:.4}", latitude, longitude));
+                }
+                record.timezone = location.time_zone.map(str::to_string);
+            }
+        }
+
+        if let Some(reader) = &asn_reader {
+            // Ranges missing from the ASN database only lose the org
+            if let Ok(asn) = reader.lookup::<geoip2::Asn>(address) {
+                record.org = match (
+                    asn.autonomous_system_number,
+                    asn.autonomous_system_organization,
+                ) {
+                    (Some(number), Some(organization)) => {
+                        Some(format!("AS{} {}", number, organization))
+                    }
+                    (Some(number), None) => Some(format!("AS{}", number)),
+                    (None, organization) => organization.map(str::to_string),
+                };
+            }
+        }
+
+        Ok(record)
+    }
+}
+
+fn english_name(names: Option<std::collections::BTreeMap<&str, &str>>) -> Option<String> {
+    names.and_then(|names| names.get("en").map(|name| name.to_string()))
+}
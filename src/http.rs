@@ -0,0 +1,307 @@
+use std::io::Read;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use log::warn;
+use reqwest::blocking::Client;
+use reqwest::{Method, Proxy};
+use samp::amx::{Allocator, Amx};
+use samp::cell::AmxString;
+use samp::error::{AmxError, AmxResult};
+use samp::native;
+
+use crate::jobs::{ErrorCode, Job, JobError};
+
+#[derive(Clone, serde_derive::Deserialize)]
+#[serde(default)]
+pub struct HttpClientConfig {
+    pub connect_timeout_ms: u64,
+    /// Whole request timeout, natives may override it per request.
+    pub timeout_ms: u64,
+    /// Proxy url used for every scheme.
+    pub proxy: Option<String>,
+    pub user_agent: String,
+    pub accept_invalid_certs: bool,
+    pub https_only: bool,
+    pub pool_max_idle_per_host: usize,
+    pub pool_idle_timeout_secs: u64,
+    /// Larger response bodies fail with `TooLarge`, each byte takes a cell of the script's heap.
+    pub max_body_bytes: usize,
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        HttpClientConfig {
+            connect_timeout_ms: 5000,
+            timeout_ms: 15000,
+            proxy: None,
+            user_agent: format!("iorp_core/{}", env!("CARGO_PKG_VERSION")),
+            accept_invalid_certs: false,
+            https_only: false,
+            pool_max_idle_per_host: 8,
+            pool_idle_timeout_secs: 90,
+            max_body_bytes: 8192,
+        }
+    }
+}
+
+/// One client for the whole plugin so every native shares its connection pool.
+static CLIENT: RwLock<Option<Client>> = RwLock::new(None);
+
+pub fn build_client(config: &HttpClientConfig) -> Result<Client, reqwest::Error> {
+    let mut builder = Client::builder()
+        .connect_timeout(Duration::from_millis(config.connect_timeout_ms))
+        .timeout(Duration::from_millis(config.timeout_ms))
+        .user_agent(config.user_agent.as_str())
+        .danger_accept_invalid_certs(config.accept_invalid_certs)
+        .https_only(config.https_only)
+        .pool_max_idle_per_host(config.pool_max_idle_per_host)
+        .pool_idle_timeout(Duration::from_secs(config.pool_idle_timeout_secs));
+
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(Proxy::all(proxy.as_str())?);
+    }
+
+    builder.build()
+}
+
+/// Replaces the shared client, requests already running keep the old one.
+pub fn configure(config: &HttpClientConfig) -> Result<(), reqwest::Error> {
+    let built = build_client(config)?;
+    *CLIENT.write().unwrap() = Some(built);
+    Ok(())
+}
+
+/// Returns the shared client, building a default one on first use.
+pub fn client() -> Client {
+    if let Some(client) = CLIENT.read().unwrap().as_ref() {
+        return client.clone();
+    }
+
+    let mut shared = CLIENT.write().unwrap();
+    shared
+        .get_or_insert_with(|| {
+            build_client(&HttpClientConfig::default()).unwrap_or_else(|_| Client::new())
+        })
+        .clone()
+}
+
+struct HttpResponse {
+    status: u16,
+    body: String,
+    headers: String,
+}
+
+pub struct HttpJob {
+    request_id: u32,
+    method: Method,
+    url: String,
+    body: String,
+    headers: Vec<(String, String)>,
+    timeout: Option<Duration>,
+    max_body_bytes: usize,
+    response: Option<HttpResponse>,
+}
+
+impl HttpJob {
+    /// Builds a request from native arguments, `None` when the method is not valid.
+    fn new(method: &str, url: String, body: String, headers: &str, timeout: u32) -> Option<Self> {
+        let method = Method::from_bytes(method.trim().to_uppercase().as_bytes()).ok()?;
+
+        Some(HttpJob {
+            request_id: 0,
+            method,
+            url,
+            body,
+            headers: parse_headers(headers),
+            timeout: if timeout > 0 {
+                Some(Duration::from_millis(timeout as u64))
+            } else {
+                None
+            },
+            max_body_bytes: HttpClientConfig::default().max_body_bytes,
+            response: None,
+        })
+    }
+}
+
+/// Parses `Name: value` lines, skipping anything without a colon.
+fn parse_headers(headers: &str) -> Vec<(String, String)> {
+    headers
+        .lines()
+        .filter_map(|line| {
+            let mut split = line.splitn(2, ':');
+            let name = split.next()?.trim();
+            let value = split.next()?.trim();
+            if name.is_empty() {
+                return None;
+            }
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+impl Job for HttpJob {
+    const CALLBACK: &'static str = "OnHttpResponse";
+    const ERROR_CALLBACK: &'static str = "OnHttpError";
+    const KIND: &'static str = "http";
+
+    fn assign_id(&mut self, id: u32) {
+        self.request_id = id;
+    }
+
+    fn player_id(&self) -> Option<u32> {
+        None
+    }
+
+    fn executor(&mut self) -> Result<(), JobError> {
+        let mut request = client().request(self.method.clone(), &self.url);
+
+        for (name, value) in &self.headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        if !self.body.is_empty() {
+            request = request.body(self.body.clone());
+        }
+        if let Some(timeout) = self.timeout {
+            request = request.timeout(timeout);
+        }
+
+        let response = request.send()?;
+        let status = response.status().as_u16();
+        let too_large = || {
+            JobError::new(
+                ErrorCode::TooLarge,
+                format!("response body is larger than {} bytes", self.max_body_bytes),
+            )
+        };
+        if let Some(length) = response.content_length() {
+            if length > self.max_body_bytes as u64 {
+                return Err(too_large());
+            }
+        }
+
+        let headers = response
+            .headers()
+            .iter()
+            .map(|(name, value)| format!("{}: {}", name, value.to_str().unwrap_or("")))
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        // Content-Length can be missing or wrong, so the read itself is capped too
+        let mut bytes = Vec::new();
+        response
+            .take(self.max_body_bytes as u64 + 1)
+            .read_to_end(&mut bytes)
+            .map_err(|e| JobError::new(ErrorCode::Network, e.to_string()))?;
+        if bytes.len() > self.max_body_bytes {
+            return Err(too_large());
+        }
+        let body = String::from_utf8_lossy(&bytes).to_string();
+
+        self.response = Some(HttpResponse {
+            status,
+            body,
+            headers,
+        });
+
+        Ok(())
+    }
+
+    fn push(&self, amx: &Amx, allocator: &Allocator) -> AmxResult<()> {
+        let response = self.response.as_ref().ok_or(AmxError::NotFound)?;
+
+        amx.push(allocator.allot_string(&response.headers)?)?;
+        amx.push(allocator.allot_string(&response.body)?)?;
+        amx.push(response.status as u32)?;
+        amx.push(self.request_id)
+    }
+
+    fn push_error(&self, amx: &Amx, allocator: &Allocator, error: &JobError) -> AmxResult<()> {
+        amx.push(allocator.allot_string(&error.message)?)?;
+        amx.push(error.code as u32)?;
+        amx.push(self.request_id)
+    }
+}
+
+impl super::Plugin {
+    fn add_http_job(&mut self, amx: &Amx, job: Option<HttpJob>) -> AmxResult<u32> {
+        if !self.config.features.http {
+            warn!("HttpRequest: feature is disabled");
+            return Ok(0);
+        }
+
+        let mut job = match job {
+            Some(job) => job,
+            None => {
+                warn!("HttpRequest: invalid method");
+                return Ok(0);
+            }
+        };
+
+        job.max_body_bytes = self.config.http.max_body_bytes;
+
+        match self.http.add_job(&self.pool, amx, job) {
+            Ok(id) => Ok(id),
+            Err(_e) => {
+                warn!("HttpRequest: {}", _e);
+                Ok(0)
+            }
+        }
+    }
+
+    #[native(name = "HttpRequest")]
+    pub fn native_http_request(
+        &mut self,
+        amx: &Amx,
+        method: AmxString,
+        url: AmxString,
+        body: AmxString,
+        headers: AmxString,
+        timeout: u32,
+    ) -> AmxResult<u32> {
+        let job = HttpJob::new(
+            &method.to_string(),
+            url.to_string(),
+            body.to_string(),
+            &headers.to_string(),
+            timeout,
+        );
+        self.add_http_job(amx, job)
+    }
+
+    #[native(name = "HttpGet")]
+    pub fn native_http_get(
+        &mut self,
+        amx: &Amx,
+        url: AmxString,
+        headers: AmxString,
+    ) -> AmxResult<u32> {
+        let job = HttpJob::new(
+            "GET",
+            url.to_string(),
+            String::new(),
+            &headers.to_string(),
+            0,
+        );
+        self.add_http_job(amx, job)
+    }
+
+    #[native(name = "HttpPost")]
+    pub fn native_http_post(
+        &mut self,
+        amx: &Amx,
+        url: AmxString,
+        body: AmxString,
+        headers: AmxString,
+    ) -> AmxResult<u32> {
+        let job = HttpJob::new(
+            "POST",
+            url.to_string(),
+            body.to_string(),
+            &headers.to_string(),
+            0,
+        );
+        self.add_http_job(amx, job)
+    }
+}
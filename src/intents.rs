@@ -0,0 +1,412 @@
+use std::fmt;
+use std::fs;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use regex::{Captures, Regex, RegexBuilder};
+
+/// Intent file layout:
+///
+/// ```toml
+/// [[intent]]
+/// name = "give_money"
+/// patterns = ["give {player:player} {amount:number} dollars", "pay {player:player} {amount:number}"]
+/// regex = []
+/// responses = ["Sending ${amount} to {player}."]
+///
+/// [[intent]]
+/// name = "give_money_more"
+/// requires = ["give_money"]
+/// inherit = true
+/// patterns = ["and {amount:number} more"]
+/// responses = ["Sending another ${amount} to {player}."]
+/// ```
+///
+/// Slot types are `player`, `number`, `time`, `word` and `text`, untyped slots are `text`.
+/// An intent with `requires` only matches right after one of those intents, with `inherit`
+/// it reports the previous intent's name and keeps its slots unless they are matched again.
+#[derive(serde_derive::Deserialize)]
+struct IntentFile {
+    #[serde(default)]
+    intent: Vec<IntentDefinition>,
+}
+
+#[derive(serde_derive::Deserialize)]
+struct IntentDefinition {
+    name: String,
+    #[serde(default)]
+    patterns: Vec<String>,
+    /// Raw regular expressions, named groups become slots.
+    #[serde(default)]
+    regex: Vec<String>,
+    #[serde(default)]
+    responses: Vec<String>,
+    #[serde(default)]
+    requires: Vec<String>,
+    #[serde(default)]
+    inherit: bool,
+}
+
+struct Intent {
+    name: String,
+    matchers: Vec<Regex>,
+    responses: Vec<String>,
+    requires: Vec<String>,
+    inherit: bool,
+}
+
+pub struct IntentMatch {
+    pub name: String,
+    pub slots: Vec<(String, String)>,
+    /// Templated response, `None` when the intent has no responses.
+    pub response: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct IntentError(String);
+
+impl fmt::Display for IntentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for IntentError {}
+
+fn slot_expression(kind: &str) -> Option<&'static str> {
+    match kind {
+        "player" => Some(r"[A-Za-z0-9_\[\]\.\$=@()]{1,24}"),
+        "number" => Some(r"-?\d+(?:[.,]\d+)?"),
+        "time" => Some(r"\d{1,2}(?::\d{2})?\s*(?:am|pm)?|noon|midnight|now|today|tomorrow|tonight"),
+        "word" => Some(r"\S+"),
+        "text" => Some(r".+?"),
+        _ => None,
+    }
+}
+
+/// Turns `pay {player:player} {amount:number}` into an anchored, case-insensitive regex.
+fn compile_pattern(pattern: &str) -> Result<Regex, IntentError> {
+    static SLOT: OnceLock<Regex> = OnceLock::new();
+    let slot = SLOT.get_or_init(|| Regex::new(r"\{(\w+)(?::(\w+))?\}").unwrap());
+    let mut chunks = Vec::new();
+
+    // Whitespace between chunks matches any run of whitespace in the query
+    for chunk in pattern.split_whitespace() {
+        let mut expression = String::new();
+        let mut last = 0;
+
+        for captures in slot.captures_iter(chunk) {
+            let whole = captures.get(0).unwrap();
+            expression.push_str(&regex::escape(&chunk[last..whole.start()]));
+
+            let name = &captures[1];
+            let kind = captures.get(2).map(|kind| kind.as_str()).unwrap_or("text");
+            let slot_expression = slot_expression(kind).ok_or_else(|| {
+                IntentError(format!("unknown slot type \"{}\" in \"{}\"", kind, pattern))
+            })?;
+            expression.push_str(&format!("(?P<{}>{})", name, slot_expression));
+
+            last = whole.end();
+        }
+
+        expression.push_str(&regex::escape(&chunk[last..]));
+        chunks.push(expression);
+    }
+
+    let expression = format!(r"^\s*{}\s*[?.!]*\s*$", chunks.join(r"\s+"));
+
+    RegexBuilder::new(&expression)
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| IntentError(format!("pattern \"{}\": {}", pattern, e)))
+}
+
+fn compile_regex(expression: &str) -> Result<Regex, IntentError> {
+    RegexBuilder::new(expression)
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| IntentError(format!("regex \"{}\": {}", expression, e)))
+}
+
+/// Replaces `{slot}` placeholders, unknown ones are left untouched.
+fn render(template: &str, slots: &[(String, String)]) -> String {
+    static PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+    let placeholder = PLACEHOLDER.get_or_init(|| Regex::new(r"\{(\w+)\}").unwrap());
+    placeholder
+        .replace_all(template, |captures: &Captures| {
+            slots
+                .iter()
+                .find(|(name, _)| name == &captures[1])
+                .map(|(_, value)| value.clone())
+                .unwrap_or_else(|| captures[0].to_string())
+        })
+        .to_string()
+}
+
+/// Matches queries against intents in file order, the first match wins.
+pub struct IntentEngine {
+    intents: Vec<Intent>,
+}
+
+impl IntentEngine {
+    pub fn empty() -> Self {
+        IntentEngine {
+            intents: Vec::new(),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<IntentEngine, IntentError> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| IntentError(format!("unable to read {}: {}", path, e)))?;
+        let file: IntentFile = toml::from_str(&content)
+            .map_err(|e| IntentError(format!("unable to parse {}: {}", path, e)))?;
+        IntentEngine::compile(file)
+    }
+
+    fn compile(file: IntentFile) -> Result<IntentEngine, IntentError> {
+        let mut intents = Vec::new();
+        for definition in file.intent {
+            let mut matchers = Vec::new();
+            for pattern in &definition.patterns {
+                matchers.push(compile_pattern(pattern)?);
+            }
+            for expression in &definition.regex {
+                matchers.push(compile_regex(expression)?);
+            }
+
+            intents.push(Intent {
+                name: definition.name,
+                matchers,
+                responses: definition.responses,
+                requires: definition.requires,
+                inherit: definition.inherit,
+            });
+        }
+
+        Ok(IntentEngine { intents })
+    }
+
+    pub fn len(&self) -> usize {
+        self.intents.len()
+    }
+
+    /// `last_intent` and `previous_slots` come from the player's session.
+    pub fn resolve(
+        &self,
+        query: &str,
+        last_intent: Option<&str>,
+        previous_slots: &[(String, String)],
+    ) -> Option<IntentMatch> {
+        for intent in &self.intents {
+            let follows = match last_intent {
+                Some(last) => intent.requires.iter().any(|required| required == last),
+                None => false,
+            };
+            if !intent.requires.is_empty() && !follows {
+                continue;
+            }
+
+            for matcher in &intent.matchers {
+                let captures = match matcher.captures(query) {
+                    Some(captures) => captures,
+                    None => continue,
+                };
+
+                let mut slots: Vec<(String, String)> = matcher
+                    .capture_names()
+                    .flatten()
+                    .filter_map(|name| {
+                        captures
+                            .name(name)
+                            .map(|value| (name.to_string(), value.as_str().trim().to_string()))
+                    })
+                    .collect();
+
+                let mut name = intent.name.clone();
+                if intent.inherit && follows {
+                    for (slot, value) in previous_slots {
+                        if !slots.iter().any(|(matched, _)| matched == slot) {
+                            slots.push((slot.clone(), value.clone()));
+                        }
+                    }
+                    name = last_intent.unwrap_or_default().to_string();
+                }
+
+                let response = if intent.responses.is_empty() {
+                    None
+                } else {
+                    // Vary the answer between identical queries
+                    let seed = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|time| time.subsec_nanos() as usize)
+                        .unwrap_or(0);
+                    let template = &intent.responses[seed % intent.responses.len()];
+                    Some(render(template, &slots))
+                };
+
+                return Some(IntentMatch {
+                    name,
+                    slots,
+                    response,
+                });
+            }
+        }
+
+        None
+    }
+}
+
+/// Formats slots as `name=value` lines for Pawn.
+pub fn format_slots(slots: &[(String, String)]) -> String {
+    slots
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Reads one slot back out of `format_slots` output.
+pub fn find_slot(slots: &str, name: &str) -> Option<String> {
+    slots.lines().find_map(|line| {
+        let mut split = line.splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some(key), Some(value)) if key == name => Some(value.to_string()),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTENTS: &str = r#"
+        [[intent]]
+        name = "give_money"
+        patterns = ["pay {player:player} {amount:number}"]
+        responses = ["Sending ${amount} to {player}."]
+
+        [[intent]]
+        name = "give_money_more"
+        requires = ["give_money"]
+        inherit = true
+        patterns = ["and {amount:number} more"]
+        responses = ["Sending another ${amount} to {player}."]
+
+        [[intent]]
+        name = "confirm"
+        requires = ["give_money"]
+        patterns = ["yes"]
+
+        [[intent]]
+        name = "math"
+        patterns = ["what is 2+2 (really)?"]
+        responses = ["4, {unknown}"]
+
+        [[intent]]
+        name = "weather"
+        regex = ['^weather in (?P<city>\w+)$']
+        responses = ["It is sunny in {city}."]
+    "#;
+
+    fn engine() -> IntentEngine {
+        IntentEngine::compile(toml::from_str(INTENTS).unwrap()).unwrap()
+    }
+
+    fn slots(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn literal_text_is_escaped() {
+        let pattern = compile_pattern("what is 2+2 (really)?").unwrap();
+        assert!(pattern.is_match("what is 2+2 (really)?"));
+        assert!(pattern.is_match("  what   is 2+2 (really)?!  "));
+        assert!(!pattern.is_match("what is 22 really"));
+        assert!(!pattern.is_match("what is 2+2 (really)? no"));
+    }
+
+    #[test]
+    fn typed_slots() {
+        let engine = engine();
+        let matched = engine.resolve("pay John_Doe 50.5", None, &[]).unwrap();
+        assert_eq!(matched.name, "give_money");
+        assert_eq!(
+            matched.slots,
+            slots(&[("player", "John_Doe"), ("amount", "50.5")])
+        );
+        assert_eq!(matched.response.unwrap(), "Sending $50.5 to John_Doe.");
+
+        assert!(engine.resolve("pay John_Doe lots", None, &[]).is_none());
+        assert!(engine.resolve("pay John Doe 5", None, &[]).is_none());
+    }
+
+    #[test]
+    fn matching_ignores_case_and_trailing_punctuation() {
+        let engine = engine();
+        let matched = engine.resolve("PAY john 5!", None, &[]).unwrap();
+        assert_eq!(matched.name, "give_money");
+        assert_eq!(matched.slots, slots(&[("player", "john"), ("amount", "5")]));
+
+        let matched = engine.resolve("Weather in Mumbai", None, &[]).unwrap();
+        assert_eq!(matched.slots, slots(&[("city", "Mumbai")]));
+    }
+
+    #[test]
+    fn unknown_slot_type_is_an_error() {
+        assert!(compile_pattern("pay {player:person}").is_err());
+        assert!(compile_pattern("pay {player}").is_ok());
+    }
+
+    #[test]
+    fn follow_up_inherits_slots() {
+        let engine = engine();
+        let previous = slots(&[("player", "John"), ("amount", "50")]);
+
+        let matched = engine
+            .resolve("and 20 more", Some("give_money"), &previous)
+            .unwrap();
+        assert_eq!(matched.name, "give_money");
+        assert_eq!(
+            matched.slots,
+            slots(&[("amount", "20"), ("player", "John")])
+        );
+        assert_eq!(matched.response.unwrap(), "Sending another $20 to John.");
+    }
+
+    #[test]
+    fn required_intents_only_match_after_their_parent() {
+        let engine = engine();
+        assert!(engine.resolve("and 20 more", None, &[]).is_none());
+        assert!(engine
+            .resolve("and 20 more", Some("weather"), &[])
+            .is_none());
+        assert!(engine.resolve("yes", None, &[]).is_none());
+
+        let matched = engine.resolve("yes", Some("give_money"), &[]).unwrap();
+        assert_eq!(matched.name, "confirm");
+        assert!(matched.slots.is_empty());
+        assert!(matched.response.is_none());
+    }
+
+    #[test]
+    fn unknown_placeholders_are_left_alone() {
+        let matched = engine()
+            .resolve("what is 2+2 (really)?", None, &[])
+            .unwrap();
+        assert_eq!(matched.response.unwrap(), "4, {unknown}");
+    }
+
+    #[test]
+    fn slots_round_trip_through_pawn_format() {
+        let formatted = format_slots(&slots(&[("player", "John"), ("note", "a=b")]));
+        assert_eq!(formatted, "player=John\nnote=a=b");
+        assert_eq!(find_slot(&formatted, "player").as_deref(), Some("John"));
+        assert_eq!(find_slot(&formatted, "note").as_deref(), Some("a=b"));
+        assert_eq!(find_slot(&formatted, "amount"), None);
+        assert_eq!(find_slot("", "player"), None);
+    }
+}
@@ -1,128 +1,382 @@
+use std::collections::HashMap;
+use std::fs;
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use log::error;
-use samp::amx::{Amx, AmxIdent};
-use samp::cell::AmxString;
+use log::{error, info, warn};
+use samp::amx::{Allocator, Amx};
+use samp::cell::{AmxString, Ref, UnsizedBuffer};
 use samp::error::{AmxError, AmxResult};
 use samp::native;
-use slab::Slab;
 
-#[derive(serde_derive::Deserialize)]
-struct Ip {
-    ip: Option<String>,
-    city: Option<String>,
-    region: Option<String>,
-    country: Option<String>,
-    loc: Option<String>,
-    org: Option<String>,
-    postal: Option<String>,
-    timezone: Option<String>,
+use crate::ip_provider::IpProviders;
+use crate::jobs::{push_error, Job, JobError};
+use crate::lru::LruCache;
+
+#[derive(Clone, Copy, PartialEq, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpProviderKind {
+    /// `endpoints.ip_info`, ipinfo.io by default.
+    IpInfo,
+    /// `endpoints.ip_api`, ip-api.com by default.
+    IpApi,
+    /// Local GeoLite2 City and ASN databases, reloaded when the files change.
+    Mmdb,
 }
 
-pub struct IpInfoJob {
-    job_completed: bool,
-    ident: AmxIdent,
-    player_id: u32,
-    ip: String,
-    token: String,
-    offset: u32,
-    response: Option<Ip>,
+#[derive(Clone, serde_derive::Deserialize)]
+#[serde(default)]
+pub struct IpInfoConfig {
+    /// Tried in order until one answers.
+    pub providers: Vec<IpProviderKind>,
+    /// Lookups per minute by provider name, missing or 0 is unlimited.
+    pub rate_limits: HashMap<String, usize>,
+    pub city_db: String,
+    pub asn_db: String,
+    /// Seconds a lookup is reused for, 0 disables the cache.
+    pub cache_ttl_secs: u64,
+    pub cache_size: usize,
+    /// Keeps the cache across restarts when set.
+    pub cache_file: String,
 }
 
-pub struct IpInfoPlugin {
-    pub jobs: Arc<Mutex<Slab<IpInfoJob>>>,
+impl Default for IpInfoConfig {
+    fn default() -> Self {
+        IpInfoConfig {
+            providers: vec![IpProviderKind::IpInfo],
+            rate_limits: [("ip_api".to_string(), 45)].iter().cloned().collect(),
+            city_db: "GeoLite2-City.mmdb".to_string(),
+            asn_db: "GeoLite2-ASN.mmdb".to_string(),
+            cache_ttl_secs: 86400,
+            cache_size: 2048,
+            cache_file: String::new(),
+        }
+    }
 }
 
-impl IpInfoPlugin {
-    fn executor(params: &mut IpInfoJob) -> Result<(), Box<dyn std::error::Error>> {
-        let search = format!("http://ipinfo.io/{}?token={}", params.ip, params.token);
-        let ip: Ip = reqwest::blocking::get(&search)?.json()?;
-        params.response = Some(ip);
+#[derive(Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct Ip {
+    pub ip: Option<String>,
+    pub city: Option<String>,
+    pub region: Option<String>,
+    pub country: Option<String>,
+    pub loc: Option<String>,
+    /// `AS<number> <name>`, used for VPN and hosting detection.
+    pub org: Option<String>,
+    pub postal: Option<String>,
+    pub timezone: Option<String>,
+}
 
-        Ok(())
-    }
+/// Reads the number out of `AS16509`, `16509` or `AS16509 Amazon.com, Inc.`.
+pub fn parse_asn(entry: &str) -> Option<u32> {
+    let first = entry.split_whitespace().next()?;
+    let digits = match first.get(..2) {
+        Some(prefix) if first.len() > 2 && prefix.eq_ignore_ascii_case("as") => &first[2..],
+        _ => first,
+    };
+    digits.parse().ok()
+}
 
-    fn add_job(&mut self, amx: &Amx, player_id: u32, ip: AmxString, token: AmxString, offset: u32) {
-        let ident = AmxIdent::from(amx.amx().as_ptr());
+impl Ip {
+    /// Field by name for the `IpInfo_Get*` natives, `lat`, `lon`, `asn` and `as_name`
+    /// are split out of `loc` and `org`. Empty values count as missing.
+    pub fn field(&self, name: &str) -> Option<String> {
+        let coordinate = |index: usize| {
+            self.loc
+                .as_ref()
+                .and_then(|loc| loc.split(',').nth(index))
+                .map(|value| value.trim().to_string())
+        };
 
-        let data = IpInfoJob {
-            job_completed: false,
-            ident,
-            player_id,
-            ip: ip.to_string(),
-            token: token.to_string(),
-            offset,
-            response: None,
+        let value = match name {
+            "ip" => self.ip.clone(),
+            "city" => self.city.clone(),
+            "region" => self.region.clone(),
+            "country" => self.country.clone(),
+            "loc" => self.loc.clone(),
+            "lat" => coordinate(0),
+            "lon" => coordinate(1),
+            "org" => self.org.clone(),
+            "asn" => self
+                .org
+                .as_ref()
+                .and_then(|org| parse_asn(org))
+                .map(|asn| asn.to_string()),
+            "as_name" => self
+                .org
+                .as_ref()
+                .filter(|org| parse_asn(org).is_some())
+                .and_then(|org| org.split_once(' ').map(|(_, name)| name))
+                .map(str::to_string),
+            "postal" => self.postal.clone(),
+            "timezone" => self.timezone.clone(),
+            _ => None,
         };
+        value.filter(|value| !value.is_empty())
+    }
+}
+
+/// Ids are never reused, so a handle kept past its callback reads nothing
+/// instead of another player's result.
+pub struct ResultStore {
+    next_id: u32,
+    entries: HashMap<u32, Ip>,
+}
+
+impl ResultStore {
+    pub fn new() -> Self {
+        ResultStore {
+            next_id: 1,
+            entries: HashMap::new(),
+        }
+    }
+
+    fn insert(&mut self, ip: Ip) -> u32 {
+        let id = self.next_id;
+        // Handles start at 1 so 0 is never valid in Pawn
+        self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+        self.entries.insert(id, ip);
+        id
+    }
+
+    fn get(&self, id: u32) -> Option<&Ip> {
+        self.entries.get(&id)
+    }
+
+    fn remove(&mut self, id: u32) {
+        self.entries.remove(&id);
+    }
+}
+
+/// Results handed to `OnIpInfoResponse`, readable through the `IpInfo_Get*` natives.
+pub type IpResults = Arc<Mutex<ResultStore>>;
+
+/// Owns one entry of `IpResults`, removed when the job is dropped after its callback.
+pub struct ResultHandle {
+    results: IpResults,
+    id: u32,
+}
+
+impl ResultHandle {
+    pub fn new(results: &IpResults, ip: Ip) -> Self {
+        let id = results
+            .lock()
+            .map(|mut results| results.insert(ip))
+            .unwrap_or(0);
+
+        ResultHandle {
+            results: Arc::clone(results),
+            id,
+        }
+    }
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+}
 
-        let key = self.jobs.lock().unwrap().insert(data);
-        let arc_key = Arc::new(key);
-        let share_arc_key = Arc::clone(&arc_key);
-        let slab_handle = Arc::clone(&self.jobs);
+impl Drop for ResultHandle {
+    fn drop(&mut self) {
+        if let Ok(mut results) = self.results.lock() {
+            results.remove(self.id);
+        }
+    }
+}
 
-        std::thread::spawn(move || {
-            let mut slab = slab_handle.lock().unwrap();
-            let key = *share_arc_key;
-            let params = slab.get_mut(key).unwrap();
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
 
-            match IpInfoPlugin::executor(params) {
-                Err(_e) => {
-                    error!("{}", _e);
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
+struct CacheEntry {
+    ip: Ip,
+    /// Unix time the entry stops being used, so it survives restarts.
+    expires: u64,
+}
+
+/// Lookups by ip, reused until their TTL runs out.
+pub struct IpCache {
+    entries: LruCache<String, CacheEntry>,
+    ttl: u64,
+    file: String,
+    hits: u32,
+    misses: u32,
+}
+
+impl IpCache {
+    pub fn new() -> Self {
+        let config = IpInfoConfig::default();
+        IpCache {
+            entries: LruCache::new(config.cache_size),
+            ttl: config.cache_ttl_secs,
+            file: String::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn configure(&mut self, config: &IpInfoConfig) {
+        self.entries.set_capacity(config.cache_size);
+        self.ttl = config.cache_ttl_secs;
+
+        if config.cache_file != self.file {
+            self.file = config.cache_file.clone();
+            self.load();
+        }
+    }
+
+    fn load(&mut self) {
+        if self.file.is_empty() {
+            return;
+        }
+
+        let content = match fs::read_to_string(&self.file) {
+            Ok(content) => content,
+            Err(_) => return,
+        };
+
+        match serde_json::from_str::<Vec<(String, CacheEntry)>>(&content) {
+            Ok(entries) => {
+                let now = unix_now();
+                for (ip, entry) in entries {
+                    if entry.expires > now {
+                        self.entries.insert(ip, entry);
+                    }
                 }
-                Ok(_) => (),
-            };
-
-            params.job_completed = true;
-        });
-    }
-
-    pub fn process_tick(&mut self) -> Result<(), AmxError> {
-        let mut slab = self.jobs.lock().map_err(|_| AmxError::NotFound)?;
-
-        // Collect keys of jobs to be removed
-        let mut to_remove = Vec::new();
-
-        for (_key, params) in slab.iter_mut() {
-            if params.job_completed {
-                let amx = samp::amx::get(params.ident).ok_or(AmxError::NotFound)?;
-                let index = amx.find_public("OnIpInfoResponse")?;
-                let allocator = amx.allocator();
-
-                let push_string = |value: &Option<String>| {
-                    let binding = "NaN".to_string();
-                    let str = value.as_ref().unwrap_or(&binding);
-                    amx.push(allocator.allot_string(str)?)
-                };
-
-                let response = &params.response.as_ref().ok_or(AmxError::NotFound)?;
-
-                amx.push(params.offset)?;
-                push_string(&response.postal)?;
-                push_string(&response.timezone)?;
-                push_string(&response.org)?;
-                push_string(&response.city)?;
-                push_string(&response.region)?;
-                push_string(&response.country)?;
-                push_string(&response.loc)?;
-                push_string(&response.ip)?;
-                amx.push(params.player_id)?;
-                amx.exec(index)?;
-
-                // Add key to to_remove
-                to_remove.push(_key);
+                info!("loaded {} cached ip lookups", self.entries.len());
             }
+            Err(_e) => error!("unable to read {}: {}", self.file, _e),
+        }
+    }
+
+    pub fn save(&self) {
+        if self.file.is_empty() {
+            return;
+        }
+
+        let now = unix_now();
+        let entries: Vec<(&String, &CacheEntry)> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.expires > now)
+            .collect();
+
+        let written = serde_json::to_string(&entries)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(&self.file, json).map_err(|e| e.to_string()));
+
+        if let Err(_e) = written {
+            error!("unable to write {}: {}", self.file, _e);
         }
+    }
 
-        // Remove processed items
-        for key in to_remove {
-            slab.remove(key);
+    pub fn get(&mut self, ip: &str) -> Option<Ip> {
+        let key = ip.trim().to_string();
+        let found = match self.entries.get(&key) {
+            Some(entry) if entry.expires > unix_now() => Some(entry.ip.clone()),
+            Some(_) => {
+                self.entries.remove(&key);
+                None
+            }
+            None => None,
+        };
+
+        match found {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
         }
+        found
+    }
+
+    pub fn insert(&mut self, ip: &str, info: Ip) {
+        if self.ttl == 0 {
+            return;
+        }
+
+        let entry = CacheEntry {
+            ip: info,
+            expires: unix_now() + self.ttl,
+        };
+        self.entries.insert(ip.trim().to_string(), entry);
+    }
+
+    pub fn flush(&mut self) {
+        self.entries.clear();
+        self.hits = 0;
+        self.misses = 0;
+        self.save();
+    }
+}
+
+/// Everything a worker needs to look an ip up, results are added to the cache.
+pub struct IpLookup {
+    providers: Arc<IpProviders>,
+    cache: Arc<Mutex<IpCache>>,
+    token: String,
+}
+
+impl IpLookup {
+    pub fn lookup(&self, ip: &str) -> Result<Ip, JobError> {
+        let info = self.providers.lookup(ip, &self.token)?;
+
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.insert(ip, info.clone());
+        }
+        Ok(info)
+    }
+}
+
+pub struct IpInfoJob {
+    lookup: IpLookup,
+    results: IpResults,
+    player_id: u32,
+    ip: String,
+    offset: u32,
+    response: Option<ResultHandle>,
+}
+
+impl Job for IpInfoJob {
+    const CALLBACK: &'static str = "OnIpInfoResponse";
+    const ERROR_CALLBACK: &'static str = "OnIpInfoError";
+    const KIND: &'static str = "ip_info";
+
+    fn player_id(&self) -> Option<u32> {
+        Some(self.player_id)
+    }
+
+    fn executor(&mut self) -> Result<(), JobError> {
+        let info = self.lookup.lookup(&self.ip)?;
+        self.response = Some(ResultHandle::new(&self.results, info));
 
         Ok(())
     }
+
+    fn push(&self, amx: &Amx, _allocator: &Allocator) -> AmxResult<()> {
+        let response = self.response.as_ref().ok_or(AmxError::NotFound)?;
+
+        amx.push(self.offset)?;
+        amx.push(response.id())?;
+        amx.push(self.player_id)
+    }
+
+    fn push_error(&self, amx: &Amx, allocator: &Allocator, error: &JobError) -> AmxResult<()> {
+        push_error(amx, allocator, self.player_id, error, self.offset)
+    }
 }
 
 impl super::Plugin {
+    /// An empty token falls back to `tokens.ip_info`.
+    pub fn ip_lookup(&self, token: &str) -> IpLookup {
+        IpLookup {
+            providers: Arc::clone(&self.ip_providers),
+            cache: Arc::clone(&self.ip_cache),
+            token: token.to_string(),
+        }
+    }
+
     #[native(name = "IpInfo")]
     pub fn native_ip_info(
         &mut self,
@@ -131,8 +385,130 @@ impl super::Plugin {
         ip: AmxString,
         token: AmxString,
         offset: u32,
+    ) -> AmxResult<u32> {
+        if !self.config.features.ip_info {
+            warn!("IpInfo: feature is disabled");
+            return Ok(0);
+        }
+
+        let mut job = IpInfoJob {
+            lookup: self.ip_lookup(&token.to_string()),
+            results: Arc::clone(&self.ip_results),
+            player_id,
+            ip: ip.to_string(),
+            offset,
+            response: None,
+        };
+
+        let cached = self
+            .ip_cache
+            .lock()
+            .ok()
+            .and_then(|mut cache| cache.get(&job.ip));
+        if let Some(info) = cached {
+            job.response = Some(ResultHandle::new(&self.ip_results, info));
+            return Ok(self.ip.add_completed(amx, job));
+        }
+
+        match self.ip.add_job(&self.pool, amx, job) {
+            Ok(id) => Ok(id),
+            Err(_e) => {
+                warn!("IpInfo: {}", _e);
+                Ok(0)
+            }
+        }
+    }
+
+    #[native(name = "IpInfoCacheFlush")]
+    pub fn native_ip_info_cache_flush(&mut self, _amx: &Amx) -> AmxResult<bool> {
+        match self.ip_cache.lock() {
+            Ok(mut cache) => {
+                cache.flush();
+                Ok(true)
+            }
+            Err(_) => Ok(false),
+        }
+    }
+
+    #[native(name = "IpInfoCacheStats")]
+    pub fn native_ip_info_cache_stats(
+        &mut self,
+        _amx: &Amx,
+        mut hits: Ref<i32>,
+        mut misses: Ref<i32>,
+        mut size: Ref<i32>,
     ) -> AmxResult<bool> {
-        IpInfoPlugin::add_job(&mut self.ip, amx, player_id, ip, token, offset);
+        let cache = match self.ip_cache.lock() {
+            Ok(cache) => cache,
+            Err(_) => return Ok(false),
+        };
+
+        *hits = cache.hits as i32;
+        *misses = cache.misses as i32;
+        *size = cache.entries.len() as i32;
         Ok(true)
     }
+
+    /// Looks up a result by the handle passed to `OnIpInfoResponse`.
+    fn ip_field(&self, handle: u32, field: &AmxString) -> Option<String> {
+        let results = self.ip_results.lock().ok()?;
+        results.get(handle)?.field(&field.to_string())
+    }
+
+    #[native(name = "IpInfo_HasField")]
+    pub fn native_ip_info_has_field(
+        &mut self,
+        _amx: &Amx,
+        handle: u32,
+        field: AmxString,
+    ) -> AmxResult<bool> {
+        Ok(self.ip_field(handle, &field).is_some())
+    }
+
+    #[native(name = "IpInfo_GetString")]
+    pub fn native_ip_info_get_string(
+        &mut self,
+        _amx: &Amx,
+        handle: u32,
+        field: AmxString,
+        dest: UnsizedBuffer,
+        size: usize,
+    ) -> AmxResult<bool> {
+        match self.ip_field(handle, &field) {
+            Some(value) => {
+                let mut buffer = dest.into_sized_buffer(size);
+                let _ = samp::cell::string::put_in_buffer(&mut buffer, &value);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// 0.0 when the field is missing or not a number.
+    #[native(name = "IpInfo_GetFloat")]
+    pub fn native_ip_info_get_float(
+        &mut self,
+        _amx: &Amx,
+        handle: u32,
+        field: AmxString,
+    ) -> AmxResult<f32> {
+        let value = self
+            .ip_field(handle, &field)
+            .and_then(|value| value.parse::<f32>().ok());
+        Ok(value.unwrap_or(0.0))
+    }
+
+    /// 0 when the field is missing or not a number.
+    #[native(name = "IpInfo_GetInt")]
+    pub fn native_ip_info_get_int(
+        &mut self,
+        _amx: &Amx,
+        handle: u32,
+        field: AmxString,
+    ) -> AmxResult<i32> {
+        let value = self
+            .ip_field(handle, &field)
+            .and_then(|value| value.parse::<f64>().ok());
+        Ok(value.map(|value| value as i32).unwrap_or(0))
+    }
 }
@@ -0,0 +1,520 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::StatusCode;
+
+use crate::config::{endpoint_url, Config};
+use crate::geoip::GeoIp;
+use crate::http::client;
+use crate::ip_info::{Ip, IpProviderKind};
+use crate::jobs::{ErrorCode, JobError};
+
+/// How long a provider is skipped after answering 429 Too Many Requests.
+const QUOTA_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// Characters left as they are when an address goes into a url path.
+const ADDRESS: &AsciiSet = &NON_ALPHANUMERIC.remove(b'.').remove(b':');
+
+/// `{endpoint}/{ip}` with the script-supplied address escaped.
+fn address_url(endpoint: &str, ip: &str) -> String {
+    endpoint_url(endpoint, &utf8_percent_encode(ip, ADDRESS).to_string())
+}
+
+/// Sends `request`, a 429 becomes `RateLimited` so the provider is put on cooldown.
+fn send(request: RequestBuilder) -> Result<Response, JobError> {
+    let response = request.send()?;
+    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        return Err(JobError::new(
+            ErrorCode::RateLimited,
+            "429 Too Many Requests",
+        ));
+    }
+    Ok(response.error_for_status()?)
+}
+
+/// A source of `Ip` lookups, each one normalizes its answer to ipinfo.io's fields.
+pub trait IpProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// `token` overrides the configured token for providers that use one.
+    fn lookup(&self, ip: &str, token: &str) -> Result<Ip, JobError>;
+}
+
+struct IpInfoProvider {
+    endpoint: String,
+    token: String,
+}
+
+impl IpProvider for IpInfoProvider {
+    fn name(&self) -> &'static str {
+        "ip_info"
+    }
+
+    fn lookup(&self, ip: &str, token: &str) -> Result<Ip, JobError> {
+        let token = if token.is_empty() { &self.token } else { token };
+        let mut request = client().get(address_url(&self.endpoint, ip));
+        // In a header rather than the query string, so it stays out of logs and proxies
+        if !token.is_empty() {
+            request = request.bearer_auth(token);
+        }
+        let info = send(request)?.json()?;
+        Ok(info)
+    }
+}
+
+#[derive(serde_derive::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IpApiResponse {
+    status: String,
+    message: Option<String>,
+    query: Option<String>,
+    country_code: Option<String>,
+    region_name: Option<String>,
+    city: Option<String>,
+    zip: Option<String>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    timezone: Option<String>,
+    #[serde(rename = "as")]
+    asn: Option<String>,
+}
+
+/// ip-api.com style `GET {endpoint}/{ip}` answering with `status` and camelCase fields.
+struct IpApiProvider {
+    endpoint: String,
+}
+
+impl IpProvider for IpApiProvider {
+    fn name(&self) -> &'static str {
+        "ip_api"
+    }
+
+    fn lookup(&self, ip: &str, _token: &str) -> Result<Ip, JobError> {
+        let search = address_url(&self.endpoint, ip);
+        let response: IpApiResponse = send(client().get(&search))?.json()?;
+
+        if response.status != "success" {
+            let status = response.status;
+            let message = response.message.unwrap_or(status);
+            let code = match message.as_str() {
+                "invalid query" | "private range" | "reserved range" => ErrorCode::InvalidInput,
+                _ => ErrorCode::Unknown,
+            };
+            return Err(JobError::new(code, message));
+        }
+
+        let loc = match (response.lat, response.lon) {
+            (Some(lat), Some(lon)) => Some(format!("{:.4},{:.4}", lat, lon)),
+            _ => None,
+        };
+
+        Ok(Ip {
+            ip: response.query,
+            city: response.city,
+            region: response.region_name,
+            country: response.country_code,
+            loc,
+            org: response.asn,
+            postal: response.zip,
+            timezone: response.timezone,
+        })
+    }
+}
+
+struct MmdbProvider {
+    geoip: Arc<GeoIp>,
+}
+
+impl IpProvider for MmdbProvider {
+    fn name(&self) -> &'static str {
+        "mmdb"
+    }
+
+    fn lookup(&self, ip: &str, _token: &str) -> Result<Ip, JobError> {
+        let record = self.geoip.lookup(ip)?;
+        Ok(Ip {
+            ip: Some(ip.to_string()),
+            city: record.city,
+            region: record.region,
+            country: record.country,
+            loc: record.loc,
+            org: record.org,
+            postal: record.postal,
+            timezone: record.timezone,
+        })
+    }
+}
+
+/// Lookups allowed per minute, plus a cooldown once the provider reports its quota is used up.
+struct RateLimit {
+    per_minute: usize,
+    recent: VecDeque<Instant>,
+    cooldown_until: Option<Instant>,
+}
+
+impl RateLimit {
+    fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        if let Some(until) = self.cooldown_until {
+            if now < until {
+                return false;
+            }
+            self.cooldown_until = None;
+        }
+
+        if self.per_minute == 0 {
+            return true;
+        }
+
+        while let Some(oldest) = self.recent.front() {
+            if now.duration_since(*oldest) < Duration::from_secs(60) {
+                break;
+            }
+            self.recent.pop_front();
+        }
+
+        if self.recent.len() >= self.per_minute {
+            return false;
+        }
+        self.recent.push_back(now);
+        true
+    }
+}
+
+/// What a provider was built from, its rate limit state survives a reload while this is unchanged.
+#[derive(PartialEq)]
+struct ProviderSettings {
+    kind: IpProviderKind,
+    endpoint: String,
+    token: String,
+    per_minute: usize,
+}
+
+struct Provider {
+    inner: Box<dyn IpProvider>,
+    settings: ProviderSettings,
+    limit: Arc<Mutex<RateLimit>>,
+}
+
+/// Configured providers in failover order.
+pub struct IpProviders {
+    providers: Vec<Provider>,
+}
+
+impl IpProviders {
+    /// Providers whose settings match one in `previous` share its rate limit state.
+    pub fn new(config: &Config, geoip: &Arc<GeoIp>, previous: Option<&IpProviders>) -> Self {
+        let providers = config
+            .ip_info
+            .providers
+            .iter()
+            .map(|kind| {
+                let (inner, endpoint, token): (Box<dyn IpProvider>, _, _) = match kind {
+                    IpProviderKind::IpInfo => (
+                        Box::new(IpInfoProvider {
+                            endpoint: config.endpoints.ip_info.clone(),
+                            token: config.tokens.ip_info.clone(),
+                        }),
+                        config.endpoints.ip_info.clone(),
+                        config.tokens.ip_info.clone(),
+                    ),
+                    IpProviderKind::IpApi => (
+                        Box::new(IpApiProvider {
+                            endpoint: config.endpoints.ip_api.clone(),
+                        }),
+                        config.endpoints.ip_api.clone(),
+                        String::new(),
+                    ),
+                    IpProviderKind::Mmdb => (
+                        Box::new(MmdbProvider {
+                            geoip: Arc::clone(geoip),
+                        }),
+                        String::new(),
+                        String::new(),
+                    ),
+                };
+
+                let per_minute = config
+                    .ip_info
+                    .rate_limits
+                    .get(inner.name())
+                    .cloned()
+                    .unwrap_or(0);
+                let settings = ProviderSettings {
+                    kind: *kind,
+                    endpoint,
+                    token,
+                    per_minute,
+                };
+
+                let limit = previous
+                    .and_then(|previous| {
+                        previous
+                            .providers
+                            .iter()
+                            .find(|provider| provider.settings == settings)
+                    })
+                    .map(|provider| Arc::clone(&provider.limit))
+                    .unwrap_or_else(|| {
+                        Arc::new(Mutex::new(RateLimit {
+                            per_minute,
+                            recent: VecDeque::new(),
+                            cooldown_until: None,
+                        }))
+                    });
+
+                Provider {
+                    inner,
+                    settings,
+                    limit,
+                }
+            })
+            .collect();
+
+        IpProviders { providers }
+    }
+
+    /// Tries each provider in order, skipping those over their rate limit.
+    pub fn lookup(&self, ip: &str, token: &str) -> Result<Ip, JobError> {
+        let mut failures = Vec::new();
+        let mut all_limited = true;
+
+        for provider in &self.providers {
+            let name = provider.inner.name();
+            let allowed = provider
+                .limit
+                .lock()
+                .map(|mut limit| limit.try_acquire())
+                .unwrap_or(false);
+            if !allowed {
+                debug!("ip_info: {} is rate limited, skipping", name);
+                failures.push(format!("{}: rate limited", name));
+                continue;
+            }
+
+            match provider.inner.lookup(ip, token) {
+                Ok(mut info) => {
+                    if info.ip.is_none() {
+                        info.ip = Some(ip.to_string());
+                    }
+                    return Ok(info);
+                }
+                // Another provider won't make a malformed ip valid
+                Err(e) if e.code == ErrorCode::InvalidInput => return Err(e),
+                Err(e) if e.code == ErrorCode::RateLimited => {
+                    if let Ok(mut limit) = provider.limit.lock() {
+                        limit.cooldown_until = Some(Instant::now() + QUOTA_COOLDOWN);
+                    }
+                    warn!("ip_info: {} quota is used up, pausing it", name);
+                    failures.push(format!("{}: {}", name, e.message));
+                }
+                Err(e) => {
+                    warn!("ip_info: {} failed: {}", name, e);
+                    failures.push(format!("{}: {}", name, e.message));
+                    all_limited = false;
+                }
+            }
+        }
+
+        if failures.is_empty() {
+            return Err(JobError::new(
+                ErrorCode::Unknown,
+                "no ip_info provider is configured",
+            ));
+        }
+        let code = if all_limited {
+            ErrorCode::RateLimited
+        } else {
+            ErrorCode::Unknown
+        };
+        Err(JobError::new(code, failures.join("; ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct FakeProvider {
+        name: &'static str,
+        code: Option<ErrorCode>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl IpProvider for FakeProvider {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn lookup(&self, _ip: &str, _token: &str) -> Result<Ip, JobError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            match self.code {
+                Some(code) => Err(JobError::new(code, "failed")),
+                None => Ok(Ip {
+                    ip: None,
+                    city: Some(self.name.to_string()),
+                    region: None,
+                    country: None,
+                    loc: None,
+                    org: None,
+                    postal: None,
+                    timezone: None,
+                }),
+            }
+        }
+    }
+
+    /// A provider that answers with `code`, or succeeds when it is `None`, and counts its calls.
+    fn fake(
+        name: &'static str,
+        code: Option<ErrorCode>,
+        per_minute: usize,
+    ) -> (Provider, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let provider = Provider {
+            inner: Box::new(FakeProvider {
+                name,
+                code,
+                calls: Arc::clone(&calls),
+            }),
+            settings: ProviderSettings {
+                kind: IpProviderKind::IpApi,
+                endpoint: name.to_string(),
+                token: String::new(),
+                per_minute,
+            },
+            limit: Arc::new(Mutex::new(limit(per_minute))),
+        };
+        (provider, calls)
+    }
+
+    fn failure(providers: &IpProviders, ip: &str) -> JobError {
+        match providers.lookup(ip, "") {
+            Ok(_) => panic!("{} should fail", ip),
+            Err(e) => e,
+        }
+    }
+
+    fn limit(per_minute: usize) -> RateLimit {
+        RateLimit {
+            per_minute,
+            recent: VecDeque::new(),
+            cooldown_until: None,
+        }
+    }
+
+    #[test]
+    fn rate_limit_window() {
+        let mut limit = limit(2);
+        assert!(limit.try_acquire());
+        assert!(limit.try_acquire());
+        assert!(!limit.try_acquire());
+
+        // Lookups older than a minute no longer count
+        let expired = Instant::now() - Duration::from_secs(61);
+        limit.recent.iter_mut().for_each(|time| *time = expired);
+        assert!(limit.try_acquire());
+        assert_eq!(limit.recent.len(), 1);
+    }
+
+    #[test]
+    fn unlimited_without_per_minute() {
+        let mut limit = limit(0);
+        assert!((0..1000).all(|_| limit.try_acquire()));
+    }
+
+    #[test]
+    fn cooldown_blocks_until_it_ends() {
+        let mut limit = limit(0);
+        limit.cooldown_until = Some(Instant::now() + Duration::from_secs(60));
+        assert!(!limit.try_acquire());
+
+        limit.cooldown_until = Some(Instant::now() - Duration::from_secs(1));
+        assert!(limit.try_acquire());
+        assert!(limit.cooldown_until.is_none());
+    }
+
+    #[test]
+    fn fails_over_to_the_next_provider() {
+        let (down, down_calls) = fake("down", Some(ErrorCode::Network), 0);
+        let (up, _) = fake("up", None, 0);
+        let providers = IpProviders {
+            providers: vec![down, up],
+        };
+
+        let info = providers.lookup("1.2.3.4", "").unwrap();
+        assert_eq!(info.city.as_deref(), Some("up"));
+        assert_eq!(info.ip.as_deref(), Some("1.2.3.4"));
+        assert_eq!(down_calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn quota_exhaustion_starts_a_cooldown() {
+        let (quota, quota_calls) = fake("quota", Some(ErrorCode::RateLimited), 0);
+        let (up, _) = fake("up", None, 0);
+        let providers = IpProviders {
+            providers: vec![quota, up],
+        };
+
+        assert!(providers.lookup("1.2.3.4", "").is_ok());
+        assert!(providers.lookup("1.2.3.4", "").is_ok());
+        assert_eq!(quota_calls.load(Ordering::SeqCst), 1);
+        assert!(providers.providers[0]
+            .limit
+            .lock()
+            .unwrap()
+            .cooldown_until
+            .is_some());
+    }
+
+    #[test]
+    fn invalid_input_stops_the_failover() {
+        let (strict, _) = fake("strict", Some(ErrorCode::InvalidInput), 0);
+        let (up, up_calls) = fake("up", None, 0);
+        let providers = IpProviders {
+            providers: vec![strict, up],
+        };
+
+        let error = failure(&providers, "not an ip");
+        assert_eq!(error.code, ErrorCode::InvalidInput);
+        assert_eq!(up_calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn rate_limited_only_when_every_provider_is() {
+        let (first, _) = fake("first", None, 1);
+        let (second, _) = fake("second", None, 1);
+        let providers = IpProviders {
+            providers: vec![first, second],
+        };
+        assert!(providers.lookup("1.2.3.4", "").is_ok());
+        assert!(providers.lookup("1.2.3.4", "").is_ok());
+
+        let error = failure(&providers, "1.2.3.4");
+        assert_eq!(error.code, ErrorCode::RateLimited);
+
+        let (limited, _) = fake("limited", None, 1);
+        let (down, _) = fake("down", Some(ErrorCode::Network), 0);
+        let providers = IpProviders {
+            providers: vec![limited, down],
+        };
+        assert!(providers.lookup("1.2.3.4", "").is_ok());
+
+        let error = failure(&providers, "1.2.3.4");
+        assert_eq!(error.code, ErrorCode::Unknown);
+        assert!(error.message.contains("limited: rate limited"));
+    }
+
+    #[test]
+    fn no_providers() {
+        let providers = IpProviders {
+            providers: Vec::new(),
+        };
+        assert_eq!(failure(&providers, "1.2.3.4").code, ErrorCode::Unknown);
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::HashSet;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use log::{error, info, warn};
+use samp::amx::{Allocator, Amx};
+use samp::cell::AmxString;
+use samp::error::{AmxError, AmxResult};
+use samp::native;
+
+use crate::ip_info::{parse_asn, IpLookup};
+use crate::jobs::{push_error, Job, JobError};
+
+#[derive(Clone, serde_derive::Deserialize)]
+#[serde(default)]
+pub struct IpRiskConfig {
+    /// One ASN per line (`AS16509` or `16509`), `#` starts a comment.
+    pub hosting_asn_file: String,
+    pub vpn_asn_file: String,
+    /// One exit node address per line.
+    pub tor_exit_file: String,
+    /// Matched against the lowercased org name when the ASN isn't listed. Broad words
+    /// like "cloud" or "server" also appear in residential ISP names, so they are left out.
+    pub hosting_keywords: Vec<String>,
+    pub vpn_keywords: Vec<String>,
+    pub tor_score: u32,
+    pub vpn_score: u32,
+    pub hosting_score: u32,
+}
+
+impl Default for IpRiskConfig {
+    fn default() -> Self {
+        let strings = |words: &[&str]| words.iter().map(|word| word.to_string()).collect();
+
+        IpRiskConfig {
+            hosting_asn_file: "hosting_asns.txt".to_string(),
+            vpn_asn_file: "vpn_asns.txt".to_string(),
+            tor_exit_file: "tor_exits.txt".to_string(),
+            hosting_keywords: strings(&[
+                "hosting",
+                "datacenter",
+                "data center",
+                "vps",
+                "colocation",
+                "dedicated",
+            ]),
+            vpn_keywords: strings(&["vpn", "proxy", "mullvad", "private internet access"]),
+            tor_score: 100,
+            vpn_score: 90,
+            hosting_score: 70,
+        }
+    }
+}
+
+/// Passed to `OnIpRiskResult` as `type`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpKind {
+    Residential = 0,
+    Hosting = 1,
+    Vpn = 2,
+    Tor = 3,
+}
+
+pub struct IpRisk {
+    kind: IpKind,
+    score: u32,
+    reason: String,
+}
+
+/// Non-empty lines without `#` comments, a missing file is an empty list.
+fn read_list(path: &str) -> Vec<String> {
+    if path.is_empty() || !std::path::Path::new(path).exists() {
+        return Vec::new();
+    }
+
+    match fs::read_to_string(path) {
+        Ok(content) => content
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect(),
+        Err(_e) => {
+            error!("unable to read {}: {}", path, _e);
+            Vec::new()
+        }
+    }
+}
+
+/// ASN and exit node lists, replaced as a whole on reload.
+pub struct RiskLists {
+    hosting: HashSet<u32>,
+    vpn: HashSet<u32>,
+    tor: HashSet<IpAddr>,
+}
+
+impl RiskLists {
+    pub fn empty() -> Self {
+        RiskLists {
+            hosting: HashSet::new(),
+            vpn: HashSet::new(),
+            tor: HashSet::new(),
+        }
+    }
+
+    pub fn load(config: &IpRiskConfig) -> Self {
+        let asns = |path: &str| -> HashSet<u32> {
+            read_list(path)
+                .iter()
+                .filter_map(|entry| {
+                    let asn = parse_asn(entry);
+                    if asn.is_none() {
+                        warn!("{}: ignoring \"{}\"", path, entry);
+                    }
+                    asn
+                })
+                .collect()
+        };
+
+        let lists = RiskLists {
+            hosting: asns(&config.hosting_asn_file),
+            vpn: asns(&config.vpn_asn_file),
+            tor: read_list(&config.tor_exit_file)
+                .iter()
+                .filter_map(|entry| entry.parse().ok())
+                .collect(),
+        };
+
+        info!(
+            "loaded {} hosting asns, {} vpn asns and {} tor exit nodes",
+            lists.hosting.len(),
+            lists.vpn.len(),
+            lists.tor.len()
+        );
+        lists
+    }
+
+    pub fn is_tor(&self, ip: &str) -> bool {
+        match ip.trim().parse::<IpAddr>() {
+            Ok(address) => self.tor.contains(&address),
+            Err(_) => false,
+        }
+    }
+
+    /// Tor exit nodes first, then listed ASNs, then org name keywords, VPN before hosting.
+    pub fn classify(&self, config: &IpRiskConfig, ip: &str, org: Option<&str>) -> IpRisk {
+        if self.is_tor(ip) {
+            return IpRisk {
+                kind: IpKind::Tor,
+                score: config.tor_score,
+                reason: "listed tor exit node".to_string(),
+            };
+        }
+
+        let org = org.unwrap_or("");
+        let asn = parse_asn(org);
+        let name = org.to_lowercase();
+        let keyword = |keywords: &[String]| {
+            keywords
+                .iter()
+                .find(|keyword| !keyword.is_empty() && name.contains(&keyword.to_lowercase()))
+                .cloned()
+        };
+
+        let checks = [
+            (
+                IpKind::Vpn,
+                config.vpn_score,
+                &self.vpn,
+                &config.vpn_keywords,
+                "vpn",
+            ),
+            (
+                IpKind::Hosting,
+                config.hosting_score,
+                &self.hosting,
+                &config.hosting_keywords,
+                "hosting",
+            ),
+        ];
+
+        for (kind, score, listed, keywords, label) in checks.iter() {
+            if let Some(asn) = asn.filter(|asn| listed.contains(asn)) {
+                return IpRisk {
+                    kind: *kind,
+                    score: *score,
+                    reason: format!("AS{} is a listed {} provider", asn, label),
+                };
+            }
+            if let Some(keyword) = keyword(keywords) {
+                return IpRisk {
+                    kind: *kind,
+                    score: *score,
+                    reason: format!("org \"{}\" matches \"{}\"", org, keyword),
+                };
+            }
+        }
+
+        IpRisk {
+            kind: IpKind::Residential,
+            score: 0,
+            reason: "no match".to_string(),
+        }
+    }
+}
+
+pub struct IpRiskJob {
+    lookup: IpLookup,
+    lists: Arc<RiskLists>,
+    config: IpRiskConfig,
+    player_id: u32,
+    ip: String,
+    offset: u32,
+    response: Option<IpRisk>,
+}
+
+impl Job for IpRiskJob {
+    const CALLBACK: &'static str = "OnIpRiskResult";
+    const ERROR_CALLBACK: &'static str = "OnIpRiskError";
+    const KIND: &'static str = "ip_info";
+
+    fn player_id(&self) -> Option<u32> {
+        Some(self.player_id)
+    }
+
+    fn executor(&mut self) -> Result<(), JobError> {
+        let info = self.lookup.lookup(&self.ip)?;
+        let risk = self
+            .lists
+            .classify(&self.config, &self.ip, info.org.as_deref());
+        self.response = Some(risk);
+
+        Ok(())
+    }
+
+    fn push(&self, amx: &Amx, allocator: &Allocator) -> AmxResult<()> {
+        let response = self.response.as_ref().ok_or(AmxError::NotFound)?;
+
+        amx.push(self.offset)?;
+        amx.push(allocator.allot_string(&response.reason)?)?;
+        amx.push(response.score)?;
+        amx.push(response.kind as u32)?;
+        amx.push(allocator.allot_string(&self.ip)?)?;
+        amx.push(self.player_id)
+    }
+
+    fn push_error(&self, amx: &Amx, allocator: &Allocator, error: &JobError) -> AmxResult<()> {
+        push_error(amx, allocator, self.player_id, error, self.offset)
+    }
+}
+
+impl super::Plugin {
+    #[native(name = "IpRiskCheck")]
+    pub fn native_ip_risk_check(
+        &mut self,
+        amx: &Amx,
+        player_id: u32,
+        ip: AmxString,
+        offset: u32,
+    ) -> AmxResult<u32> {
+        if !self.config.features.ip_risk {
+            warn!("IpRiskCheck: feature is disabled");
+            return Ok(0);
+        }
+
+        let ip = ip.to_string();
+        let mut job = IpRiskJob {
+            lookup: self.ip_lookup(""),
+            lists: Arc::clone(&self.risk_lists),
+            config: self.config.ip_risk.clone(),
+            player_id,
+            ip,
+            offset,
+            response: None,
+        };
+
+        // Tor exit nodes and cached lookups need no network call
+        if self.risk_lists.is_tor(&job.ip) {
+            job.response = Some(self.risk_lists.classify(&job.config, &job.ip, None));
+            return Ok(self.ip_risk.add_completed(amx, job));
+        }
+        let cached = self
+            .ip_cache
+            .lock()
+            .ok()
+            .and_then(|mut cache| cache.get(&job.ip));
+        if let Some(info) = cached {
+            job.response = Some(self.risk_lists.classify(
+                &job.config,
+                &job.ip,
+                info.org.as_deref(),
+            ));
+            return Ok(self.ip_risk.add_completed(amx, job));
+        }
+
+        match self.ip_risk.add_job(&self.pool, amx, job) {
+            Ok(id) => Ok(id),
+            Err(_e) => {
+                warn!("IpRiskCheck: {}", _e);
+                Ok(0)
+            }
+        }
+    }
+
+    /// Rereads the ASN and exit node lists, checks already running keep the old ones.
+    #[native(name = "IpRiskReload")]
+    pub fn native_ip_risk_reload(&mut self, _amx: &Amx) -> AmxResult<bool> {
+        self.risk_lists = Arc::new(RiskLists::load(&self.config.ip_risk));
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lists() -> RiskLists {
+        RiskLists {
+            hosting: [16509].iter().cloned().collect(),
+            vpn: [9009].iter().cloned().collect(),
+            tor: ["185.220.101.1".parse().unwrap()].iter().cloned().collect(),
+        }
+    }
+
+    fn classify(ip: &str, org: Option<&str>) -> (IpKind, u32) {
+        let risk = lists().classify(&IpRiskConfig::default(), ip, org);
+        (risk.kind, risk.score)
+    }
+
+    #[test]
+    fn parses_asns() {
+        assert_eq!(parse_asn("AS16509 Amazon.com, Inc."), Some(16509));
+        assert_eq!(parse_asn("as123"), Some(123));
+        assert_eq!(parse_asn("123"), Some(123));
+        assert_eq!(parse_asn("  AS42  "), Some(42));
+        assert_eq!(parse_asn("AS"), None);
+        assert_eq!(parse_asn("ASX12"), None);
+        assert_eq!(parse_asn("Comcast Cable"), None);
+        assert_eq!(parse_asn("aé1"), None);
+        assert_eq!(parse_asn(""), None);
+    }
+
+    #[test]
+    fn tor_comes_first() {
+        assert_eq!(
+            classify("185.220.101.1", Some("AS9009 M247 Ltd")),
+            (IpKind::Tor, 100)
+        );
+        assert_eq!(classify(" 185.220.101.1 ", None), (IpKind::Tor, 100));
+    }
+
+    #[test]
+    fn listed_asns() {
+        // VPN is checked first, so its keywords win over a hosting listing
+        assert_eq!(
+            classify("1.2.3.4", Some("AS16509 Some VPN Reseller")),
+            (IpKind::Vpn, 90)
+        );
+        assert_eq!(
+            classify("1.2.3.4", Some("AS16509 Amazon.com, Inc.")),
+            (IpKind::Hosting, 70)
+        );
+        assert_eq!(
+            classify("1.2.3.4", Some("AS9009 M247 Ltd")),
+            (IpKind::Vpn, 90)
+        );
+    }
+
+    #[test]
+    fn vpn_checked_before_hosting() {
+        assert_eq!(
+            classify("1.2.3.4", Some("AS1 Proxy Hosting Ltd")),
+            (IpKind::Vpn, 90)
+        );
+        assert_eq!(
+            classify("1.2.3.4", Some("AS2 Example Datacenter")),
+            (IpKind::Hosting, 70)
+        );
+    }
+
+    #[test]
+    fn residential_by_default() {
+        assert_eq!(
+            classify("1.2.3.4", Some("AS9829 National Internet Backbone")),
+            (IpKind::Residential, 0)
+        );
+        assert_eq!(
+            classify("1.2.3.4", Some("AS7922 Comcast Cable Communications")),
+            (IpKind::Residential, 0)
+        );
+        assert_eq!(classify("1.2.3.4", None), (IpKind::Residential, 0));
+    }
+}
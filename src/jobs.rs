@@ -0,0 +1,542 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::error;
+use samp::amx::{Allocator, Amx, AmxIdent};
+use samp::error::{AmxError, AmxResult};
+use samp::native;
+
+use crate::pool::{PoolError, WorkerPool};
+
+/// Error codes passed to the `On*Error` callbacks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    Unknown = 1,
+    Network = 2,
+    Timeout = 3,
+    HttpStatus = 4,
+    Parse = 5,
+    /// The request itself is malformed, e.g. an invalid math expression.
+    InvalidInput = 6,
+    /// The lookup succeeded but has no data, e.g. an ip missing from the database.
+    NotFound = 7,
+    /// Every provider that could answer is over its rate limit.
+    RateLimited = 8,
+    /// The job panicked, a bug in the plugin rather than in the request.
+    Internal = 9,
+    /// The response is bigger than the script could receive.
+    TooLarge = 10,
+}
+
+#[derive(Debug)]
+pub struct JobError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl JobError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        JobError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for JobError {}
+
+/// Runs the executor, a panic becomes an `Internal` error so the script still gets a callback.
+fn run_executor<J: Job>(job: &mut J) -> Result<(), JobError> {
+    let result = match panic::catch_unwind(AssertUnwindSafe(|| job.executor())) {
+        Ok(result) => result,
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            Err(JobError::new(
+                ErrorCode::Internal,
+                format!("job panicked: {}", message),
+            ))
+        }
+    };
+
+    if let Err(_e) = &result {
+        error!("{}: {}", J::KIND, _e);
+    }
+    result
+}
+
+impl From<reqwest::Error> for JobError {
+    fn from(error: reqwest::Error) -> Self {
+        let code = if error.is_timeout() {
+            ErrorCode::Timeout
+        } else if error.is_status() {
+            ErrorCode::HttpStatus
+        } else if error.is_decode() {
+            ErrorCode::Parse
+        } else if error.is_connect() || error.is_request() {
+            ErrorCode::Network
+        } else {
+            ErrorCode::Unknown
+        };
+
+        JobError::new(code, error.to_string())
+    }
+}
+
+/// A unit of blocking work started by a native and answered through a public callback.
+pub trait Job: Send + 'static {
+    /// Name of the public which receives the result.
+    const CALLBACK: &'static str;
+
+    /// Name of the public which receives a failure.
+    const ERROR_CALLBACK: &'static str;
+
+    /// Worker pool kind used for per-kind limits.
+    const KIND: &'static str;
+
+    /// Receives the request id before the job is queued.
+    fn assign_id(&mut self, _id: u32) {}
+
+    /// Player the job was started for, used to cancel it on disconnect.
+    fn player_id(&self) -> Option<u32>;
+
+    /// Runs on a background thread, stores whatever `push` needs later.
+    fn executor(&mut self) -> Result<(), JobError>;
+
+    /// Pushes the callback arguments, last argument first.
+    fn push(&self, amx: &Amx, allocator: &Allocator) -> AmxResult<()>;
+
+    /// Pushes the error callback arguments, last argument first.
+    fn push_error(&self, amx: &Amx, allocator: &Allocator, error: &JobError) -> AmxResult<()>;
+}
+
+/// Request ids are unique across every queue so `CancelRequest` needs no kind.
+static NEXT_JOB_ID: AtomicU32 = AtomicU32::new(1);
+
+fn next_job_id() -> u32 {
+    loop {
+        let id = NEXT_JOB_ID.fetch_add(1, Ordering::SeqCst);
+        // 0 is what natives return on failure
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+/// Server-side view of a job which has not been delivered yet.
+struct Pending {
+    ident: AmxIdent,
+    player_id: Option<u32>,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// A job whose executor has returned, waiting for the server thread.
+struct Completed<J> {
+    id: u32,
+    ident: AmxIdent,
+    job: J,
+    result: Result<(), JobError>,
+}
+
+/// Executors own their job while they run and hand it back through a channel,
+/// so the server thread only ever waits for results that are already done.
+pub struct JobQueue<J: Job> {
+    sender: Sender<Completed<J>>,
+    receiver: Receiver<Completed<J>>,
+    pending: HashMap<u32, Pending>,
+}
+
+impl<J: Job> JobQueue<J> {
+    pub fn new() -> Self {
+        let (sender, receiver) = channel();
+        JobQueue {
+            sender,
+            receiver,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Queues `job` on the pool and returns its request id.
+    pub fn add_job(&mut self, pool: &WorkerPool, amx: &Amx, mut job: J) -> Result<u32, PoolError> {
+        let id = next_job_id();
+        let ident = AmxIdent::from(amx.amx().as_ptr());
+        let sender = self.sender.clone();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let worker_cancelled = Arc::clone(&cancelled);
+        let player_id = job.player_id();
+        job.assign_id(id);
+
+        let submitted = pool.submit(J::KIND, move || {
+            // Skip the work entirely if the request was cancelled while queued
+            if worker_cancelled.load(Ordering::SeqCst) {
+                return;
+            }
+
+            let result = run_executor(&mut job);
+
+            // The receiver is gone only while the plugin unloads
+            let _ = sender.send(Completed {
+                id,
+                ident,
+                job,
+                result,
+            });
+        });
+
+        submitted.map(|_| {
+            self.pending.insert(
+                id,
+                Pending {
+                    ident,
+                    player_id,
+                    cancelled,
+                },
+            );
+            id
+        })
+    }
+
+    /// Queues a job whose result is already known, it is delivered on the next tick
+    /// without touching the worker pool.
+    pub fn add_completed(&mut self, amx: &Amx, job: J) -> u32 {
+        self.queue_completed(amx, job, Ok(()))
+    }
+
+    /// Runs a cheap job on the server thread, its callback still fires from `process_tick`.
+    pub fn run_inline(&mut self, amx: &Amx, mut job: J) -> u32 {
+        let result = run_executor(&mut job);
+        self.queue_completed(amx, job, result)
+    }
+
+    fn queue_completed(&mut self, amx: &Amx, mut job: J, result: Result<(), JobError>) -> u32 {
+        let id = next_job_id();
+        let ident = AmxIdent::from(amx.amx().as_ptr());
+        job.assign_id(id);
+
+        self.pending.insert(
+            id,
+            Pending {
+                ident,
+                player_id: job.player_id(),
+                cancelled: Arc::new(AtomicBool::new(false)),
+            },
+        );
+
+        // The receiver lives in self, so this cannot fail
+        let _ = self.sender.send(Completed {
+            id,
+            ident,
+            job,
+            result,
+        });
+        id
+    }
+
+    fn cancel_where(&mut self, predicate: impl Fn(&Pending) -> bool) -> usize {
+        let ids: Vec<u32> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| predicate(pending))
+            .map(|(id, _)| *id)
+            .collect();
+
+        ids.into_iter().filter(|id| self.cancel(*id)).count()
+    }
+
+    /// Delivers finished jobs until the budget runs out, the rest wait for the next tick.
+    pub fn process_tick(&mut self, budget: &mut TickBudget) {
+        // Every received job is consumed here, whether its callback runs or not
+        while !budget.exhausted() {
+            let params = match self.receiver.try_recv() {
+                Ok(params) => params,
+                Err(_) => break,
+            };
+
+            // Cancelled requests have no pending entry left
+            if self.pending.remove(&params.id).is_none() {
+                continue;
+            }
+
+            budget.spend();
+
+            // A failing callback only loses its own job
+            if let Err(_e) = JobQueue::deliver(&params) {
+                error!(
+                    "{}: unable to deliver request {}: {}",
+                    J::KIND,
+                    params.id,
+                    _e
+                );
+            }
+        }
+    }
+
+    fn deliver(params: &Completed<J>) -> Result<(), AmxError> {
+        let amx = samp::amx::get(params.ident).ok_or(AmxError::NotFound)?;
+        let allocator = amx.allocator();
+
+        let index = match &params.result {
+            Ok(_) => {
+                let index = amx.find_public(J::CALLBACK)?;
+                params.job.push(amx, &allocator)?;
+                index
+            }
+            Err(error) => {
+                let index = amx.find_public(J::ERROR_CALLBACK)?;
+                params.job.push_error(amx, &allocator, error)?;
+                index
+            }
+        };
+
+        amx.exec(index)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, serde_derive::Deserialize)]
+#[serde(default)]
+pub struct TickConfig {
+    /// Callbacks delivered per server tick across all queues.
+    pub max_callbacks: usize,
+    /// Time spent delivering callbacks per server tick.
+    pub max_millis: u64,
+}
+
+impl Default for TickConfig {
+    fn default() -> Self {
+        TickConfig {
+            max_callbacks: 64,
+            max_millis: 5,
+        }
+    }
+}
+
+/// Limits how much of a server tick is spent running callbacks.
+pub struct TickBudget {
+    deadline: Instant,
+    remaining: usize,
+}
+
+impl TickBudget {
+    pub fn start(config: &TickConfig) -> Self {
+        TickBudget {
+            deadline: Instant::now() + Duration::from_millis(config.max_millis),
+            remaining: config.max_callbacks,
+        }
+    }
+
+    pub fn exhausted(&self) -> bool {
+        self.remaining == 0 || Instant::now() >= self.deadline
+    }
+
+    fn spend(&mut self) {
+        self.remaining = self.remaining.saturating_sub(1);
+    }
+}
+
+/// Type-erased access to every queue the plugin owns.
+pub trait AnyJobQueue {
+    fn kind(&self) -> &'static str;
+
+    /// Drops request `id`, returning false if this queue does not own it.
+    fn cancel(&mut self, id: u32) -> bool;
+
+    /// Drops every request started for `player_id`, returning how many were dropped.
+    fn cancel_player(&mut self, player_id: u32) -> usize;
+
+    /// Drops every request owned by the script `ident`, returning how many were dropped.
+    fn cancel_amx(&mut self, ident: AmxIdent) -> usize;
+
+    fn process_tick(&mut self, budget: &mut TickBudget);
+}
+
+impl<J: Job> AnyJobQueue for JobQueue<J> {
+    fn kind(&self) -> &'static str {
+        J::KIND
+    }
+
+    fn cancel(&mut self, id: u32) -> bool {
+        match self.pending.remove(&id) {
+            Some(pending) => {
+                pending.cancelled.store(true, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn cancel_player(&mut self, player_id: u32) -> usize {
+        self.cancel_where(|pending| pending.player_id == Some(player_id))
+    }
+
+    fn cancel_amx(&mut self, ident: AmxIdent) -> usize {
+        self.cancel_where(|pending| pending.ident == ident)
+    }
+
+    fn process_tick(&mut self, budget: &mut TickBudget) {
+        JobQueue::process_tick(self, budget)
+    }
+}
+
+impl super::Plugin {
+    #[native(name = "CancelRequest")]
+    pub fn native_cancel_request(&mut self, _amx: &Amx, id: u32) -> AmxResult<bool> {
+        Ok(self.job_queues().into_iter().any(|queue| queue.cancel(id)))
+    }
+
+    /// The one place per-player state is dropped: pending requests, the chat translation
+    /// language and the Alexa session. It is not automatic, scripts have to call it from
+    /// `OnPlayerDisconnect` or the next player on that id inherits all of it.
+    #[native(name = "CancelPlayerRequests")]
+    pub fn native_cancel_player_requests(
+        &mut self,
+        _amx: &Amx,
+        player_id: u32,
+    ) -> AmxResult<usize> {
+        Ok(self.forget_player(player_id))
+    }
+}
+
+/// Pushes a string argument, "NaN" when the value is missing.
+pub fn push_string(amx: &Amx, allocator: &Allocator, value: &Option<String>) -> AmxResult<()> {
+    let binding = "NaN".to_string();
+    let str: &String = value.as_ref().unwrap_or(&binding);
+    amx.push(allocator.allot_string(str)?)
+}
+
+/// Pushes the common `(playerid, errorcode, message[], offset)` error arguments.
+pub fn push_error(
+    amx: &Amx,
+    allocator: &Allocator,
+    player_id: u32,
+    error: &JobError,
+    offset: u32,
+) -> AmxResult<()> {
+    amx.push(offset)?;
+    amx.push(allocator.allot_string(&error.message)?)?;
+    amx.push(error.code as u32)?;
+    amx.push(player_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestJob {
+        player_id: u32,
+    }
+
+    impl Job for TestJob {
+        const CALLBACK: &'static str = "OnTestResponse";
+        const ERROR_CALLBACK: &'static str = "OnTestError";
+        const KIND: &'static str = "test";
+
+        fn player_id(&self) -> Option<u32> {
+            Some(self.player_id)
+        }
+
+        fn executor(&mut self) -> Result<(), JobError> {
+            Ok(())
+        }
+
+        fn push(&self, _amx: &Amx, _allocator: &Allocator) -> AmxResult<()> {
+            Ok(())
+        }
+
+        fn push_error(
+            &self,
+            _amx: &Amx,
+            _allocator: &Allocator,
+            _error: &JobError,
+        ) -> AmxResult<()> {
+            Ok(())
+        }
+    }
+
+    /// Queues a finished job the way a worker would hand it back.
+    fn complete(queue: &mut JobQueue<TestJob>, player_id: u32) -> u32 {
+        let id = next_job_id();
+        let ident = AmxIdent::from(std::ptr::null_mut());
+        queue.pending.insert(
+            id,
+            Pending {
+                ident,
+                player_id: Some(player_id),
+                cancelled: Arc::new(AtomicBool::new(false)),
+            },
+        );
+        let _ = queue.sender.send(Completed {
+            id,
+            ident,
+            job: TestJob { player_id },
+            result: Ok(()),
+        });
+        id
+    }
+
+    fn budget(max_callbacks: usize, max_millis: u64) -> TickBudget {
+        TickBudget::start(&TickConfig {
+            max_callbacks,
+            max_millis,
+        })
+    }
+
+    #[test]
+    fn budget_runs_out_of_callbacks() {
+        let mut budget = budget(2, 60_000);
+        assert!(!budget.exhausted());
+        budget.spend();
+        assert!(!budget.exhausted());
+        budget.spend();
+        assert!(budget.exhausted());
+        budget.spend();
+        assert!(budget.exhausted());
+    }
+
+    #[test]
+    fn budget_runs_out_of_time() {
+        assert!(budget(64, 0).exhausted());
+    }
+
+    #[test]
+    fn exhausted_budget_leaves_jobs_for_the_next_tick() {
+        let mut queue = JobQueue::new();
+        for player_id in 0..3 {
+            complete(&mut queue, player_id);
+        }
+
+        let mut budget = budget(0, 60_000);
+        JobQueue::process_tick(&mut queue, &mut budget);
+
+        assert_eq!(queue.pending.len(), 3);
+        assert_eq!(queue.receiver.try_iter().count(), 3);
+    }
+
+    #[test]
+    fn cancelled_jobs_do_not_spend_the_budget() {
+        let mut queue = JobQueue::new();
+        complete(&mut queue, 1);
+        complete(&mut queue, 1);
+        assert_eq!(queue.cancel_player(1), 2);
+
+        let mut budget = budget(1, 60_000);
+        JobQueue::process_tick(&mut queue, &mut budget);
+
+        assert!(!budget.exhausted());
+        assert_eq!(queue.receiver.try_iter().count(), 0);
+    }
+}
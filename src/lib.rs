@@ -1,49 +1,281 @@
-use alexa::AlexaPlugin;
-use ip_info::IpInfoPlugin;
-use log::info;
-use math::MathPlugin;
-use samp::amx::Amx;
+use alexa::AlexaJob;
+use alexa_session::SessionStore;
+use chat_translation::ChatTranslateJob;
+use cidr::BanList;
+use config::Config;
+use expr::Expr;
+use geoip::GeoIp;
+use http::HttpJob;
+use intents::IntentEngine;
+use ip_info::{IpCache, IpInfoJob, IpResults, ResultStore};
+use ip_provider::IpProviders;
+use ip_risk::{IpRiskJob, RiskLists};
+use jobs::{AnyJobQueue, JobQueue, TickBudget};
+use log::{error, info};
+use lru::LruCache;
+use math::MathJob;
+use pool::WorkerPool;
+use samp::amx::{Amx, AmxIdent};
 use samp::initialize_plugin;
 use samp::plugin::SampPlugin;
-use slab::Slab;
+use std::collections::HashMap;
 use std::io;
 use std::sync::{Arc, Mutex};
+use translation::{TranslateJob, TranslationCache};
 
 mod alexa;
+mod alexa_session;
+mod chat_translation;
+mod cidr;
+mod config;
 mod email;
+mod expr;
+mod geoip;
+mod http;
+mod intents;
 mod ip_info;
+mod ip_provider;
+mod ip_risk;
+mod jobs;
+mod lru;
 mod math;
 mod native_string;
+mod pool;
+mod translation;
+mod units;
 
 struct Plugin {
-    alexa: AlexaPlugin,
-    ip: IpInfoPlugin,
-    math: MathPlugin,
+    config: Config,
+    pool: WorkerPool,
+    next_queue: usize,
+    alexa: JobQueue<AlexaJob>,
+    intents: Arc<IntentEngine>,
+    alexa_sessions: Arc<Mutex<SessionStore>>,
+    ip: JobQueue<IpInfoJob>,
+    geoip: Arc<GeoIp>,
+    ip_providers: Arc<IpProviders>,
+    ip_cache: Arc<Mutex<IpCache>>,
+    ip_results: IpResults,
+    ip_risk: JobQueue<IpRiskJob>,
+    risk_lists: Arc<RiskLists>,
+    range_bans: BanList,
+    math: JobQueue<MathJob>,
+    /// Variables bound with `MathSetVar`, only seen by the synchronous natives.
+    math_variables: HashMap<String, f64>,
+    math_cache: LruCache<String, Expr>,
+    http: JobQueue<HttpJob>,
+    translate: JobQueue<TranslateJob>,
+    translation_cache: Arc<Mutex<TranslationCache>>,
+    chat_translate: JobQueue<ChatTranslateJob>,
+    /// Preferred chat language per player id.
+    player_languages: HashMap<u32, String>,
+}
+
+impl Plugin {
+    fn job_queues(&mut self) -> Vec<&mut dyn AnyJobQueue> {
+        vec![
+            &mut self.alexa,
+            &mut self.ip,
+            &mut self.ip_risk,
+            &mut self.math,
+            &mut self.http,
+            &mut self.translate,
+            &mut self.chat_translate,
+        ]
+    }
+
+    fn apply_config(&mut self, config: Config) {
+        if let Err(_e) = http::configure(&config.http) {
+            error!("unable to build http client: {}", _e);
+        }
+
+        // Queued work finishes on the old workers before they exit
+        if config.pool != self.config.pool {
+            self.pool = WorkerPool::new(config.pool.clone());
+        }
+
+        if let Ok(mut cache) = self.translation_cache.lock() {
+            cache.configure(&config.translate);
+        }
+        if let Ok(mut cache) = self.ip_cache.lock() {
+            cache.configure(&config.ip_info);
+        }
+
+        if config.ip_info.city_db != self.config.ip_info.city_db
+            || config.ip_info.asn_db != self.config.ip_info.asn_db
+        {
+            self.geoip = Arc::new(GeoIp::new(&config.ip_info.city_db, &config.ip_info.asn_db));
+        }
+        self.ip_providers = Arc::new(IpProviders::new(
+            &config,
+            &self.geoip,
+            Some(&self.ip_providers),
+        ));
+
+        self.risk_lists = Arc::new(RiskLists::load(&config.ip_risk));
+
+        self.math_cache.set_capacity(config.math.cache_size);
+
+        self.intents = Arc::new(Plugin::load_intents(&config));
+        if let Ok(mut sessions) = self.alexa_sessions.lock() {
+            sessions.configure(
+                config.alexa.history_length,
+                config.alexa.session_expiry_secs,
+            );
+        }
+
+        log::set_max_level(config.log_level());
+        config::set_log_prefix(&config.logging.prefix);
+        self.config = config;
+    }
+
+    /// Drops everything kept for a player id so the next player to get it starts clean:
+    /// pending requests, the chat language and the Alexa session.
+    /// Returns how many pending requests were cancelled.
+    fn forget_player(&mut self, player_id: u32) -> usize {
+        let cancelled = self
+            .job_queues()
+            .into_iter()
+            .map(|queue| queue.cancel_player(player_id))
+            .sum();
+        self.player_languages.remove(&player_id);
+        if let Ok(mut sessions) = self.alexa_sessions.lock() {
+            sessions.reset(player_id);
+        }
+        cancelled
+    }
+
+    /// A missing or broken intent file leaves Alexa answering with its fallback.
+    fn load_intents(config: &Config) -> IntentEngine {
+        let path = &config.alexa.intents_file;
+        if path.is_empty() || !std::path::Path::new(path).exists() {
+            return IntentEngine::empty();
+        }
+
+        match IntentEngine::load(path) {
+            Ok(engine) => {
+                info!("loaded {} alexa intents", engine.len());
+                engine
+            }
+            Err(_e) => {
+                error!("{}", _e);
+                IntentEngine::empty()
+            }
+        }
+    }
+
+    /// Loads the config file, keeping the current config when the file is invalid.
+    fn reload_config(&mut self) -> bool {
+        match Config::load() {
+            Ok(config) => {
+                self.apply_config(config);
+                true
+            }
+            Err(_e) => {
+                error!("{}", _e);
+                false
+            }
+        }
+    }
 }
 
 impl SampPlugin for Plugin {
     fn on_load(&mut self) {
+        if !self.reload_config() {
+            info!("using default configuration");
+            self.apply_config(Config::default());
+        }
+
         info!("IORP Core. Loaded");
     }
 
     fn on_unload(&mut self) {
+        if let Ok(cache) = self.translation_cache.lock() {
+            cache.save();
+        }
+        if let Ok(cache) = self.ip_cache.lock() {
+            cache.save();
+        }
+
         info!("IORP Core. unloaded");
     }
 
-    fn on_amx_unload(&mut self, _unloaded_amx: &Amx) {}
+    fn on_amx_unload(&mut self, unloaded_amx: &Amx) {
+        let ident = AmxIdent::from(unloaded_amx.amx().as_ptr());
+
+        for queue in self.job_queues() {
+            let dropped = queue.cancel_amx(ident);
+            if dropped > 0 {
+                info!(
+                    "dropped {} pending {} jobs of unloaded script",
+                    dropped,
+                    queue.kind()
+                );
+            }
+        }
+    }
 
     fn process_tick(&mut self) {
-        let _ = self.alexa.process_tick();
-        let _ = self.ip.process_tick();
-        let _ = self.math.process_tick();
+        let mut budget = TickBudget::start(&self.config.tick);
+        let first = self.next_queue;
+        let mut queues = self.job_queues();
+
+        // Start from a different queue each tick so a busy one can't starve the rest
+        let count = queues.len();
+        queues.rotate_left(first % count);
+
+        for queue in queues {
+            if budget.exhausted() {
+                break;
+            }
+            queue.process_tick(&mut budget);
+        }
+
+        self.next_queue = (first + 1) % count;
     }
 }
 
 initialize_plugin!(
     natives: [
         Plugin::native_alexa,
+        Plugin::native_alexa_get_slot,
+        Plugin::native_alexa_reset_session,
+        Plugin::native_alexa_reset_all_sessions,
         Plugin::native_math,
+        Plugin::native_math_eval,
+        Plugin::native_math_eval_int,
+        Plugin::native_math_set_var,
+        Plugin::native_math_set_var_int,
+        Plugin::native_math_clear_var,
+        Plugin::native_math_clear_vars,
         Plugin::native_ip_info,
+        Plugin::native_ip_info_cache_flush,
+        Plugin::native_ip_info_cache_stats,
+        Plugin::native_ip_info_has_field,
+        Plugin::native_ip_info_get_string,
+        Plugin::native_ip_info_get_float,
+        Plugin::native_ip_info_get_int,
+        Plugin::native_ip_risk_check,
+        Plugin::native_ip_risk_reload,
+        Plugin::native_ip_is_valid,
+        Plugin::native_ip_is_v6,
+        Plugin::native_ip_normalize,
+        Plugin::native_ip_in_range,
+        Plugin::native_range_ban_add,
+        Plugin::native_range_ban_remove,
+        Plugin::native_range_ban_check,
+        Plugin::native_range_ban_load,
+        Plugin::native_range_ban_count,
+        Plugin::native_range_ban_clear,
+        Plugin::native_translater,
+        Plugin::native_translate_cache_flush,
+        Plugin::native_translate_cache_stats,
+        Plugin::native_set_player_language,
+        Plugin::native_reset_player_language,
+        Plugin::native_get_player_language,
+        Plugin::native_translate_chat,
+        Plugin::native_cancel_request,
+        Plugin::native_cancel_player_requests,
         Plugin::native_is_string_contain_words,
         Plugin::native_sort_string,
         Plugin::native_trim_string,
@@ -59,16 +291,20 @@ initialize_plugin!(
         Plugin::native_reg_match_count,
         Plugin::native_send_http_post,
         Plugin::native_send_http_get,
+        Plugin::native_http_request,
+        Plugin::native_http_get,
+        Plugin::native_http_post,
+        Plugin::native_reload_core_config,
     ],
     {
         samp::plugin::enable_process_tick();
         let _ = fern::Dispatch::new()
             .format(|callback, message, record| {
-                callback.finish(format_args!("\t[Indian Ocean Roleplay] {}: {}", record.level().to_string().to_lowercase(), message))
+                callback.finish(format_args!("\t[{}] {}: {}", config::log_prefix(), record.level().to_string().to_lowercase(), message))
             })
              .chain(
                 fern::Dispatch::new()
-                    .level(log::LevelFilter::Info)
+                    .level(log::LevelFilter::Trace)
                     .chain(io::stdout()),
             ).chain(
                 fern::Dispatch::new()
@@ -76,18 +312,39 @@ initialize_plugin!(
                     .chain(io::stdout()),
             )
             .apply();
+        log::set_max_level(log::LevelFilter::Info);
 
-        return Plugin {
-            alexa: AlexaPlugin {
-                jobs: Arc::new(Mutex::new(Slab::new()))
-            },
-            ip: IpInfoPlugin {
-                jobs: Arc::new(Mutex::new(Slab::new()))
-            },
-            math: MathPlugin {
-                jobs: Arc::new(Mutex::new(Slab::new()))
-            }
+        let geoip = Arc::new(GeoIp::new(
+            &Config::default().ip_info.city_db,
+            &Config::default().ip_info.asn_db,
+        ));
 
+        return Plugin {
+            config: Config::default(),
+            pool: WorkerPool::new(Config::default().pool),
+            next_queue: 0,
+            alexa: JobQueue::new(),
+            intents: Arc::new(IntentEngine::empty()),
+            alexa_sessions: Arc::new(Mutex::new(SessionStore::new(
+                Config::default().alexa.history_length,
+                Config::default().alexa.session_expiry_secs,
+            ))),
+            ip: JobQueue::new(),
+            geoip: Arc::clone(&geoip),
+            ip_providers: Arc::new(IpProviders::new(&Config::default(), &geoip, None)),
+            ip_cache: Arc::new(Mutex::new(IpCache::new())),
+            ip_results: Arc::new(Mutex::new(ResultStore::new())),
+            ip_risk: JobQueue::new(),
+            risk_lists: Arc::new(RiskLists::empty()),
+            range_bans: BanList::new(),
+            math: JobQueue::new(),
+            math_variables: HashMap::new(),
+            math_cache: LruCache::new(Config::default().math.cache_size),
+            http: JobQueue::new(),
+            translate: JobQueue::new(),
+            translation_cache: Arc::new(Mutex::new(TranslationCache::new())),
+            chat_translate: JobQueue::new(),
+            player_languages: HashMap::new(),
         }
     }
 );
@@ -0,0 +1,156 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Least recently used cache, every operation is O(log n).
+pub struct LruCache<K, V> {
+    capacity: usize,
+    entries: HashMap<K, (V, u64)>,
+    /// Last use stamp to key, oldest first.
+    order: BTreeMap<u64, K>,
+    stamp: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            stamp: 0,
+        }
+    }
+
+    fn next_stamp(&mut self) -> u64 {
+        self.stamp += 1;
+        self.stamp
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let stamp = self.next_stamp();
+        let entry = self.entries.get_mut(key)?;
+
+        self.order.remove(&entry.1);
+        self.order.insert(stamp, key.clone());
+        entry.1 = stamp;
+
+        Some(&entry.0)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let stamp = self.next_stamp();
+        if let Some((_, old)) = self.entries.insert(key.clone(), (value, stamp)) {
+            self.order.remove(&old);
+        }
+        self.order.insert(stamp, key);
+        self.evict();
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, stamp) = self.entries.remove(key)?;
+        self.order.remove(&stamp);
+        Some(value)
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            let oldest = match self.order.keys().next() {
+                Some(stamp) => *stamp,
+                None => return,
+            };
+            if let Some(key) = self.order.remove(&oldest) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Entries from least to most recently used, the order to insert them back in.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.order
+            .values()
+            .filter_map(move |key| self.entries.get(key).map(|(value, _)| (key, value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(cache: &LruCache<&'static str, u32>) -> Vec<&'static str> {
+        cache.iter().map(|(key, _)| *key).collect()
+    }
+
+    #[test]
+    fn evicts_least_recently_inserted() {
+        let mut cache = LruCache::new(2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        cache.insert("c", 3);
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&"a").is_none());
+        assert_eq!(keys(&cache), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn get_and_reinsert_refresh_an_entry() {
+        let mut cache = LruCache::new(2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        assert_eq!(cache.get(&"a"), Some(&1));
+        cache.insert("c", 3);
+        assert_eq!(keys(&cache), vec!["a", "c"]);
+
+        cache.insert("a", 10);
+        cache.insert("d", 4);
+        assert_eq!(keys(&cache), vec!["a", "d"]);
+        assert_eq!(cache.get(&"a"), Some(&10));
+    }
+
+    #[test]
+    fn shrinking_evicts_oldest_first() {
+        let mut cache = LruCache::new(4);
+        for (value, key) in ["a", "b", "c", "d"].iter().enumerate() {
+            cache.insert(*key, value as u32);
+        }
+        cache.get(&"a");
+
+        cache.set_capacity(2);
+        assert_eq!(keys(&cache), vec!["d", "a"]);
+    }
+
+    #[test]
+    fn zero_capacity_stores_nothing() {
+        let mut cache = LruCache::new(0);
+        cache.insert("a", 1);
+        assert_eq!(cache.len(), 0);
+        assert!(cache.get(&"a").is_none());
+    }
+
+    #[test]
+    fn removed_entries_leave_the_order() {
+        let mut cache = LruCache::new(2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        assert_eq!(cache.remove(&"a"), Some(1));
+        cache.insert("c", 3);
+        assert_eq!(keys(&cache), vec!["b", "c"]);
+    }
+}
@@ -1,114 +1,235 @@
-use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
 
-use log::error;
-use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
-use samp::amx::{Amx, AmxIdent};
-use samp::cell::AmxString;
-use samp::error::{AmxError, AmxResult};
+use log::warn;
+use samp::amx::{Allocator, Amx};
+use samp::cell::{AmxString, Ref};
+use samp::error::AmxResult;
 use samp::native;
-use slab::Slab;
+
+use crate::expr::{evaluate, is_variable_name, Expr, ExprError, ExprErrorKind};
+use crate::jobs::{push_error, push_string, ErrorCode, Job, JobError};
+use crate::units::{expand_percent, format_number, parse_query, ParsedQuery};
+
+#[derive(Clone, serde_derive::Deserialize)]
+#[serde(default)]
+pub struct MathConfig {
+    /// Parsed expressions kept for `MathEval`, 0 disables the cache.
+    pub cache_size: usize,
+    /// Decimals shown in `OnMathResponse` results.
+    pub decimals: usize,
+    pub thousands_separator: String,
+    /// Currency code to the value of one unit in a common base, e.g. `usd = 1.0`.
+    pub currencies: HashMap<String, f64>,
+}
+
+impl Default for MathConfig {
+    fn default() -> Self {
+        let currencies = [("usd", 1.0), ("eur", 1.08), ("gbp", 1.27), ("inr", 0.012)]
+            .iter()
+            .map(|(code, rate)| (code.to_string(), *rate))
+            .collect();
+
+        MathConfig {
+            cache_size: 256,
+            decimals: 2,
+            thousands_separator: ",".to_string(),
+            currencies,
+        }
+    }
+}
 
 pub struct MathJob {
-    job_completed: bool,
-    ident: AmxIdent,
+    config: MathConfig,
     query: String,
     player_id: u32,
     offset: u32,
     response: Option<String>,
 }
 
-pub struct MathPlugin {
-    pub jobs: Arc<Mutex<Slab<MathJob>>>,
-}
+impl Job for MathJob {
+    const CALLBACK: &'static str = "OnMathResponse";
+    const ERROR_CALLBACK: &'static str = "OnMathError";
+    const KIND: &'static str = "math";
+
+    fn player_id(&self) -> Option<u32> {
+        Some(self.player_id)
+    }
+
+    fn executor(&mut self) -> Result<(), JobError> {
+        let invalid = |message: String| JobError::new(ErrorCode::InvalidInput, message);
+        let config = &self.config;
+        let query = expand_percent(&self.query);
 
-impl MathPlugin {
-    fn executor(params: &mut MathJob) -> Result<(), Box<dyn std::error::Error>> {
-        let encoded = utf8_percent_encode(&params.query, NON_ALPHANUMERIC).to_string();
-        let search = format!("https://api.mathjs.org/v4/?expr={}", encoded);
-        let response = reqwest::blocking::get(&search)?.text()?;
-        params.response = Some(response);
+        let response = match parse_query(&query, &config.currencies) {
+            ParsedQuery::Plain(expression) => {
+                let value = evaluate(&expression).map_err(|e| invalid(e.to_string()))?;
+                format_number(value, config.decimals, &config.thousands_separator)
+            }
+            ParsedQuery::Conversion(conversion) => {
+                let amount =
+                    evaluate(&conversion.expression).map_err(|e| invalid(e.to_string()))?;
+                let value = conversion.convert(amount);
+                format!(
+                    "{} {}",
+                    format_number(value, config.decimals, &config.thousands_separator),
+                    conversion.to.symbol
+                )
+            }
+            ParsedQuery::Invalid(message) => return Err(invalid(message)),
+        };
+        self.response = Some(response);
 
         Ok(())
     }
 
-    pub fn add_job(&mut self, amx: &Amx, player_id: u32, query: AmxString, offset: u32) {
-        let ident = AmxIdent::from(amx.amx().as_ptr());
+    fn push(&self, amx: &Amx, allocator: &Allocator) -> AmxResult<()> {
+        amx.push(self.offset)?;
+        push_string(amx, allocator, &self.response)?;
+        amx.push(self.player_id)
+    }
 
-        let data = MathJob {
-            job_completed: false,
-            ident,
+    fn push_error(&self, amx: &Amx, allocator: &Allocator, error: &JobError) -> AmxResult<()> {
+        push_error(amx, allocator, self.player_id, error, self.offset)
+    }
+}
+
+impl super::Plugin {
+    #[native(name = "Math")]
+    pub fn native_math(
+        &mut self,
+        amx: &Amx,
+        player_id: u32,
+        query: AmxString,
+        offset: u32,
+    ) -> AmxResult<u32> {
+        if !self.config.features.math {
+            warn!("Math: feature is disabled");
+            return Ok(0);
+        }
+
+        let job = MathJob {
+            config: self.config.math.clone(),
             query: query.to_string(),
             player_id,
             offset,
             response: None,
         };
 
-        let key = self.jobs.lock().unwrap().insert(data);
-        let arc_key = Arc::new(key);
-        let share_arc_key = Arc::clone(&arc_key);
-        let slab_handle = Arc::clone(&self.jobs);
-
-        std::thread::spawn(move || {
-            let mut slab = slab_handle.lock().unwrap();
-            let key = *share_arc_key;
-            let params = slab.get_mut(key).unwrap();
-
-            match MathPlugin::executor(params) {
-                Err(_e) => error!("{}", _e),
-                Ok(_) => (),
-            };
-
-            params.job_completed = true;
-        });
+        // Evaluation is cheap enough for the server thread
+        Ok(self.math.run_inline(amx, job))
     }
 
-    pub fn process_tick(&mut self) -> Result<(), AmxError> {
-        let mut slab = self.jobs.lock().map_err(|_| AmxError::NotFound)?;
-
-        // Collect keys of jobs to be removed
-        let mut to_remove = Vec::new();
-
-        for (_key, params) in slab.iter_mut() {
-            if params.job_completed {
-                let amx = samp::amx::get(params.ident).ok_or(AmxError::NotFound)?;
-                let index = amx.find_public("OnMathResponse")?;
-                let allocator = amx.allocator();
+    /// Evaluates with the variables set from Pawn, reusing the parsed expression when cached.
+    fn eval_expression(&mut self, input: &str) -> Result<f64, ExprError> {
+        let key = input.trim().to_string();
+        if let Some(expr) = self.math_cache.get(&key) {
+            return expr.eval(&self.math_variables);
+        }
 
-                let push_string = |value: &Option<String>| {
-                    let binding = "NaN".to_string();
-                    let str: &String = value.as_ref().unwrap_or(&binding);
-                    amx.push(allocator.allot_string(str)?)
-                };
+        let expr = Expr::parse(&key)?;
+        let value = expr.eval(&self.math_variables);
+        self.math_cache.insert(key, expr);
+        value
+    }
 
-                amx.push(params.offset)?;
-                push_string(&params.response)?;
-                amx.push(params.player_id)?;
-                amx.exec(index)?;
+    #[native(name = "MathEval")]
+    pub fn native_math_eval(
+        &mut self,
+        _amx: &Amx,
+        expression: AmxString,
+        mut result: Ref<f32>,
+        mut error: Ref<i32>,
+    ) -> AmxResult<bool> {
+        match self.eval_expression(&expression.to_string()) {
+            Ok(value) => {
+                *result = value as f32;
+                *error = 0;
+                Ok(true)
+            }
+            Err(e) => {
+                *error = e.kind as i32;
+                Ok(false)
+            }
+        }
+    }
 
-                // Add key to to_remove
-                to_remove.push(_key);
+    /// Like `MathEval`, rounding the result to the nearest integer.
+    #[native(name = "MathEvalInt")]
+    pub fn native_math_eval_int(
+        &mut self,
+        _amx: &Amx,
+        expression: AmxString,
+        mut result: Ref<i32>,
+        mut error: Ref<i32>,
+    ) -> AmxResult<bool> {
+        let value = self
+            .eval_expression(&expression.to_string())
+            .and_then(|value| {
+                let rounded = value.round();
+                if rounded < i32::MIN as f64 || rounded > i32::MAX as f64 {
+                    return Err(ExprError::eval(
+                        ExprErrorKind::Overflow,
+                        "result does not fit a cell",
+                    ));
+                }
+                Ok(rounded as i32)
+            });
+
+        match value {
+            Ok(value) => {
+                *result = value;
+                *error = 0;
+                Ok(true)
+            }
+            Err(e) => {
+                *error = e.kind as i32;
+                Ok(false)
             }
         }
+    }
 
-        // Remove processed items
-        for key in to_remove {
-            slab.remove(key);
+    fn set_math_variable(&mut self, native: &str, name: AmxString, value: f64) -> bool {
+        let name = name.to_string().to_lowercase();
+        if !is_variable_name(&name) {
+            warn!("{}: \"{}\" is not a valid variable name", native, name);
+            return false;
         }
 
-        Ok(())
+        self.math_variables.insert(name, value);
+        true
     }
-}
 
-impl super::Plugin {
-    #[native(name = "Math")]
-    pub fn native_math(
+    /// `value` is read as a float cell, pass `Float:` values or use `MathSetVarInt`.
+    #[native(name = "MathSetVar")]
+    pub fn native_math_set_var(
         &mut self,
-        amx: &Amx,
-        player_id: u32,
-        query: AmxString,
-        offset: u32,
+        _amx: &Amx,
+        name: AmxString,
+        value: f32,
     ) -> AmxResult<bool> {
-        MathPlugin::add_job(&mut self.math, amx, player_id, query, offset);
-        Ok(true)
+        Ok(self.set_math_variable("MathSetVar", name, value as f64))
+    }
+
+    #[native(name = "MathSetVarInt")]
+    pub fn native_math_set_var_int(
+        &mut self,
+        _amx: &Amx,
+        name: AmxString,
+        value: i32,
+    ) -> AmxResult<bool> {
+        Ok(self.set_math_variable("MathSetVarInt", name, value as f64))
+    }
+
+    #[native(name = "MathClearVar")]
+    pub fn native_math_clear_var(&mut self, _amx: &Amx, name: AmxString) -> AmxResult<bool> {
+        let name = name.to_string().to_lowercase();
+        Ok(self.math_variables.remove(&name).is_some())
+    }
+
+    #[native(name = "MathClearVars")]
+    pub fn native_math_clear_vars(&mut self, _amx: &Amx) -> AmxResult<usize> {
+        let count = self.math_variables.len();
+        self.math_variables.clear();
+        Ok(count)
     }
 }
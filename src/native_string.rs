@@ -5,8 +5,15 @@ use samp::amx::Amx;
 use samp::cell::{AmxString, UnsizedBuffer};
 use samp::error::AmxResult;
 use samp::native;
+use std::sync::OnceLock;
 use voca_rs::*;
 
+/// Strips `{RRGGBB}` colour codes from dialog rows.
+fn colour_codes() -> &'static Regex {
+    static COLOUR_CODES: OnceLock<Regex> = OnceLock::new();
+    COLOUR_CODES.get_or_init(|| Regex::new(r"\{(.*?)\}").unwrap())
+}
+
 impl super::Plugin {
     #[native(name = "regexMatchCount")]
     pub fn native_reg_match_count(
@@ -20,7 +27,7 @@ impl super::Plugin {
             .build()
             .expect("Invalid Regex");
         let results_count = re.find_iter(&string.to_string()).count();
-        return Ok(results_count);
+        Ok(results_count)
     }
 
     #[native(name = "GetPercentage")]
@@ -30,7 +37,7 @@ impl super::Plugin {
         value: usize,
         maximum: usize,
     ) -> AmxResult<usize> {
-        if value <= 0 {
+        if value == 0 {
             return Ok(0);
         }
         if value >= maximum {
@@ -47,7 +54,7 @@ impl super::Plugin {
         percent: usize,
         value: usize,
     ) -> AmxResult<usize> {
-        if percent <= 0 {
+        if percent == 0 {
             return Ok(0);
         }
         if percent >= 100 {
@@ -102,7 +109,7 @@ impl super::Plugin {
         let joined = vec.join("\n");
         let trim_ed = joined.trim();
         let mut buffer = response.into_sized_buffer(size);
-        let _ = samp::cell::string::put_in_buffer(&mut buffer, &trim_ed);
+        let _ = samp::cell::string::put_in_buffer(&mut buffer, trim_ed);
         Ok(true)
     }
 
@@ -118,16 +125,15 @@ impl super::Plugin {
         let input = string.to_string();
         let split = input.split("\n");
         for s in split {
-            let text = s.split("\t").nth(0).unwrap();
-            if text.len() > 0 {
-                let re = Regex::new(r"\{(.*?)\}").unwrap();
-                let result = re.replace_all(text, "");
+            let text = s.split('\t').next().unwrap();
+            if !text.is_empty() {
+                let result = colour_codes().replace_all(text, "");
                 owned_string.push_str(&result);
-                owned_string.push_str("\n");
+                owned_string.push('\n');
             }
         }
         let mut buffer = response.into_sized_buffer(size);
-        let _ = samp::cell::string::put_in_buffer(&mut buffer, &owned_string.trim());
+        let _ = samp::cell::string::put_in_buffer(&mut buffer, owned_string.trim());
         Ok(true)
     }
 
@@ -142,19 +148,17 @@ impl super::Plugin {
         let mut owned_string: String = "".to_owned();
         let input = string.to_string();
         let split = input.split("\n");
-        let mut count = 0;
-        for s in split {
-            let text = s.split("\t").nth(0).unwrap();
-            if text.len() > 0 && count != 0 {
-                let re = Regex::new(r"\{(.*?)\}").unwrap();
-                let result = re.replace_all(text, "");
+        // The first row is the header
+        for s in split.skip(1) {
+            let text = s.split('\t').next().unwrap();
+            if !text.is_empty() {
+                let result = colour_codes().replace_all(text, "");
                 owned_string.push_str(&result);
-                owned_string.push_str("\n");
+                owned_string.push('\n');
             }
-            count += 1;
         }
         let mut buffer = response.into_sized_buffer(size);
-        let _ = samp::cell::string::put_in_buffer(&mut buffer, &owned_string.trim());
+        let _ = samp::cell::string::put_in_buffer(&mut buffer, owned_string.trim());
         Ok(true)
     }
 
@@ -167,16 +171,10 @@ impl super::Plugin {
         response: UnsizedBuffer,
         size: usize,
     ) -> AmxResult<bool> {
-        let result;
         let input = string.to_string();
-        let total = input.split("\n").count();
-        if position >= total {
-            result = "null";
-        } else {
-            result = input.split("\n").nth(position).unwrap();
-        }
+        let result = input.split('\n').nth(position).unwrap_or("null");
         let mut buffer = response.into_sized_buffer(size);
-        let _ = samp::cell::string::put_in_buffer(&mut buffer, &result);
+        let _ = samp::cell::string::put_in_buffer(&mut buffer, result);
         Ok(true)
     }
 
@@ -191,7 +189,7 @@ impl super::Plugin {
         let string_input = input.to_string();
         let trim_ed = string_input.trim();
         let mut buffer = output.into_sized_buffer(size);
-        let _ = samp::cell::string::put_in_buffer(&mut buffer, &trim_ed);
+        let _ = samp::cell::string::put_in_buffer(&mut buffer, trim_ed);
         Ok(true)
     }
 
@@ -208,14 +206,13 @@ impl super::Plugin {
         let split = input.split(" ");
         let vec: Vec<&str> = split.collect();
         let total_words = count::count_words(&input, " ");
-        let result;
-        if total_words < position + 1 || vec[position].len() == 0 {
-            result = "none";
+        let result = if total_words < position + 1 || vec[position].is_empty() {
+            "none"
         } else {
-            result = vec[position];
-        }
+            vec[position]
+        };
         let mut buffer = response.into_sized_buffer(size);
-        let _ = samp::cell::string::put_in_buffer(&mut buffer, &result);
+        let _ = samp::cell::string::put_in_buffer(&mut buffer, result);
         Ok(true)
     }
 
@@ -232,12 +229,11 @@ impl super::Plugin {
         let word = search.to_string();
         let word_size = word.len() + 1;
         let index_of = index::index_of(&input, &word, 0);
-        let result;
-        if index_of == -1 {
-            result = input;
+        let result = if index_of == -1 {
+            input
         } else {
-            result = chop::substring(&input, index_of as usize + word_size, 0);
-        }
+            chop::substring(&input, index_of as usize + word_size, 0)
+        };
         let mut buffer = response.into_sized_buffer(size);
         let _ = samp::cell::string::put_in_buffer(&mut buffer, &result);
         Ok(true)
@@ -271,11 +267,8 @@ impl super::Plugin {
         // Convert the timestamp string into an i64
         let timestamp = unix_time.parse::<i64>().unwrap();
 
-        // Create a NaiveDateTime from the timestamp
-        let naive = NaiveDateTime::from_timestamp(timestamp, 0);
-
-        // Create a normal DateTime from the NaiveDateTime
-        let date_time: DateTime<Utc> = DateTime::from_utc(naive, Utc);
+        // Create a UTC DateTime from the timestamp, every u32 timestamp is in range
+        let date_time: DateTime<Utc> = DateTime::from_timestamp(timestamp, 0).unwrap_or_default();
 
         let date_time_local: DateTime<Local> = DateTime::from(date_time);
 
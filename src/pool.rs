@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+use log::error;
+
+type Task = Box<dyn FnOnce() + Send>;
+
+#[derive(Clone, PartialEq, serde_derive::Deserialize)]
+#[serde(default)]
+pub struct PoolConfig {
+    /// Number of worker threads.
+    pub workers: usize,
+    /// Tasks that may wait for a free worker before submissions are refused.
+    pub queue_depth: usize,
+    /// Maximum queued plus running tasks per job kind, unlimited when absent.
+    pub kind_limits: HashMap<String, usize>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            workers: 8,
+            queue_depth: 512,
+            kind_limits: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PoolError {
+    QueueFull,
+    KindLimit(&'static str),
+    Closed,
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PoolError::QueueFull => write!(f, "worker queue is full"),
+            PoolError::KindLimit(kind) => write!(f, "too many pending {} jobs", kind),
+            PoolError::Closed => write!(f, "worker pool is shut down"),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+/// Releases a kind slot once the task finished, even if it panicked.
+struct KindSlot(Arc<AtomicUsize>);
+
+impl Drop for KindSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub struct WorkerPool {
+    sender: SyncSender<Task>,
+    kind_limits: HashMap<String, usize>,
+    in_flight: Mutex<HashMap<&'static str, Arc<AtomicUsize>>>,
+}
+
+impl WorkerPool {
+    pub fn new(config: PoolConfig) -> Self {
+        let (sender, receiver) = sync_channel::<Task>(config.queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for id in 0..config.workers.max(1) {
+            let receiver = Arc::clone(&receiver);
+            let spawned = std::thread::Builder::new()
+                .name(format!("iorp-worker-{}", id))
+                .spawn(move || WorkerPool::worker(receiver));
+
+            if let Err(_e) = spawned {
+                error!("unable to start worker thread: {}", _e);
+            }
+        }
+
+        WorkerPool {
+            sender,
+            kind_limits: config.kind_limits,
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn worker(receiver: Arc<Mutex<Receiver<Task>>>) {
+        loop {
+            let task = match receiver.lock() {
+                Ok(receiver) => receiver.recv(),
+                Err(_) => return,
+            };
+
+            match task {
+                Ok(task) => {
+                    if panic::catch_unwind(AssertUnwindSafe(task)).is_err() {
+                        error!("worker task panicked");
+                    }
+                }
+                // Sender dropped, the pool was replaced or the plugin is unloading
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Queues `task` without blocking, refusing it when the queue or the kind limit is exhausted.
+    pub fn submit<F>(&self, kind: &'static str, task: F) -> Result<(), PoolError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let counter = {
+            let mut in_flight = self.in_flight.lock().map_err(|_| PoolError::Closed)?;
+            Arc::clone(in_flight.entry(kind).or_default())
+        };
+
+        let limit = self.kind_limits.get(kind).copied().unwrap_or(usize::MAX);
+        if counter.fetch_add(1, Ordering::SeqCst) >= limit {
+            counter.fetch_sub(1, Ordering::SeqCst);
+            return Err(PoolError::KindLimit(kind));
+        }
+
+        let slot = KindSlot(counter);
+        let task: Task = Box::new(move || {
+            let _slot = slot;
+            task();
+        });
+
+        // A refused task is dropped here, which releases its kind slot
+        match self.sender.try_send(task) {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(PoolError::QueueFull),
+            Err(TrySendError::Disconnected(_)) => Err(PoolError::Closed),
+        }
+    }
+}
@@ -1,83 +1,454 @@
-use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
-use reqwest;
-use samp::amx::{Amx, AmxIdent};
-use samp::cell::AmxString;
-use samp::error::AmxResult;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use log::{error, info, warn};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use reqwest::StatusCode;
+use samp::amx::{Allocator, Amx};
+use samp::cell::{AmxString, Ref};
+use samp::error::{AmxError, AmxResult};
 use samp::native;
 
-const TRANSLATE: &'static str = "http://localhost:7333/translate";
+use crate::http::client;
+use crate::jobs::{push_error, push_string, ErrorCode, Job, JobError};
+use crate::lru::LruCache;
+
+#[derive(Clone, Copy, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TranslateBackend {
+    /// The in-house service, `GET ?lang=&q=&encoded=` answering with plain text.
+    Local,
+    /// Any LibreTranslate compatible `POST /translate` api.
+    LibreTranslate,
+}
+
+#[derive(Clone, serde_derive::Deserialize)]
+#[serde(default)]
+pub struct TranslateConfig {
+    pub backend: TranslateBackend,
+    pub endpoint: String,
+    pub api_key: String,
+    /// Translations kept in memory, 0 disables the cache.
+    pub cache_size: usize,
+    /// Where the cache is kept across restarts, empty to keep it in memory only.
+    pub cache_file: String,
+}
+
+impl Default for TranslateConfig {
+    fn default() -> Self {
+        TranslateConfig {
+            backend: TranslateBackend::Local,
+            endpoint: "http://localhost:7333/translate".to_string(),
+            api_key: String::new(),
+            cache_size: 1000,
+            cache_file: String::new(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Translation {
+    pub text: String,
+    /// Detected source language, when the backend reports one.
+    pub source: Option<String>,
+}
+
+#[derive(serde_derive::Serialize)]
+struct LibreRequest<'a> {
+    q: &'a str,
+    source: &'a str,
+    target: &'a str,
+    format: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    api_key: &'a str,
+}
+
+#[derive(serde_derive::Deserialize)]
+struct LibreDetected {
+    language: String,
+}
+
+#[derive(serde_derive::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LibreResponse {
+    translated_text: Option<String>,
+    detected_language: Option<LibreDetected>,
+    error: Option<String>,
+}
+
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
+struct CacheEntry {
+    text: String,
+    language: String,
+    translation: String,
+    source: Option<String>,
+    /// Which backend produced it, entries from files without these are skipped.
+    #[serde(default)]
+    backend: Option<TranslateBackend>,
+    #[serde(default)]
+    endpoint: String,
+}
+
+/// Remembers translations by `(text, target language)` so repeated chat lines skip the backend.
+/// Keys are always the decoded text, whether or not the script sent it percent encoded.
+pub struct TranslationCache {
+    entries: LruCache<(String, String), Translation>,
+    file: String,
+    /// Translations from another backend or endpoint are dropped when either changes.
+    backend: TranslateBackend,
+    endpoint: String,
+    hits: u32,
+    misses: u32,
+}
+
+impl TranslationCache {
+    pub fn new() -> Self {
+        TranslationCache {
+            entries: LruCache::new(TranslateConfig::default().cache_size),
+            file: String::new(),
+            backend: TranslateBackend::Local,
+            endpoint: TranslateConfig::default().endpoint,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn configure(&mut self, config: &TranslateConfig) {
+        self.entries.set_capacity(config.cache_size);
+
+        if config.backend != self.backend || config.endpoint != self.endpoint {
+            self.backend = config.backend;
+            self.endpoint = config.endpoint.clone();
+            self.entries.clear();
+            self.hits = 0;
+            self.misses = 0;
+        }
+
+        if config.cache_file != self.file {
+            self.file = config.cache_file.clone();
+            self.load();
+        }
+    }
+
+    fn load(&mut self) {
+        if self.file.is_empty() {
+            return;
+        }
+
+        let content = match fs::read_to_string(&self.file) {
+            Ok(content) => content,
+            Err(_) => return,
+        };
+
+        match serde_json::from_str::<Vec<CacheEntry>>(&content) {
+            Ok(entries) => {
+                let backend = Some(self.backend);
+                for entry in entries {
+                    if entry.backend != backend || entry.endpoint != self.endpoint {
+                        continue;
+                    }
+                    let translation = Translation {
+                        text: entry.translation,
+                        source: entry.source,
+                    };
+                    self.entries
+                        .insert((entry.text, entry.language), translation);
+                }
+                info!("loaded {} cached translations", self.entries.len());
+            }
+            Err(_e) => error!("unable to read {}: {}", self.file, _e),
+        }
+    }
+
+    pub fn save(&self) {
+        if self.file.is_empty() {
+            return;
+        }
+
+        let entries: Vec<CacheEntry> = self
+            .entries
+            .iter()
+            .map(|((text, language), translation)| CacheEntry {
+                text: text.clone(),
+                language: language.clone(),
+                translation: translation.text.clone(),
+                source: translation.source.clone(),
+                backend: Some(self.backend),
+                endpoint: self.endpoint.clone(),
+            })
+            .collect();
+
+        let written = serde_json::to_string(&entries)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(&self.file, json).map_err(|e| e.to_string()));
+
+        if let Err(_e) = written {
+            error!("unable to write {}: {}", self.file, _e);
+        }
+    }
+
+    pub fn get(&mut self, text: &str, language: &str) -> Option<Translation> {
+        let found = self
+            .entries
+            .get(&(text.to_string(), language.to_string()))
+            .cloned();
+
+        match found {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        found
+    }
+
+    /// `config` is what the translation was made with, results from before a backend
+    /// switch are dropped so they can't refill the cleared cache.
+    pub fn insert(
+        &mut self,
+        config: &TranslateConfig,
+        text: &str,
+        language: &str,
+        translation: Translation,
+    ) {
+        if config.backend != self.backend || config.endpoint != self.endpoint {
+            return;
+        }
+        self.entries
+            .insert((text.to_string(), language.to_string()), translation);
+    }
+
+    pub fn flush(&mut self) {
+        self.entries.clear();
+        self.hits = 0;
+        self.misses = 0;
+        self.save();
+    }
+}
 
 pub fn encode(string: &str) -> String {
     utf8_percent_encode(string, NON_ALPHANUMERIC).to_string()
 }
 
+/// The text a script meant, undoing its percent encoding when `encoded` is set.
+fn plain_text(input: &str, encoded: bool) -> String {
+    if encoded {
+        percent_decode_str(input).decode_utf8_lossy().to_string()
+    } else {
+        input.to_string()
+    }
+}
+
+fn translate_local(
+    config: &TranslateConfig,
+    input: &str,
+    language: &str,
+    encoded: bool,
+) -> Result<Translation, JobError> {
+    let query = if encoded {
+        input.to_string()
+    } else {
+        encode(input)
+    };
+    let search = format!(
+        "{}?lang={}&q={}&encoded={}",
+        config.endpoint,
+        encode(language),
+        query,
+        encoded
+    );
+    let text = client().get(&search).send()?.error_for_status()?.text()?;
+
+    Ok(Translation { text, source: None })
+}
+
+fn translate_libre(
+    config: &TranslateConfig,
+    input: &str,
+    language: &str,
+    encoded: bool,
+) -> Result<Translation, JobError> {
+    let text = plain_text(input, encoded);
+    let request = LibreRequest {
+        q: &text,
+        source: "auto",
+        target: language,
+        format: "text",
+        api_key: &config.api_key,
+    };
+
+    let response = client().post(&config.endpoint).json(&request).send()?;
+    let status = response.status();
+
+    // Error pages from a proxy aren't json, the body only adds detail when it is
+    if !status.is_success() {
+        let code = if status == StatusCode::TOO_MANY_REQUESTS {
+            ErrorCode::RateLimited
+        } else {
+            ErrorCode::HttpStatus
+        };
+        let message = response
+            .json::<LibreResponse>()
+            .ok()
+            .and_then(|body| body.error)
+            .map(|error| format!("{}: {}", status, error))
+            .unwrap_or_else(|| status.to_string());
+        return Err(JobError::new(code, message));
+    }
+
+    let body: LibreResponse = response.json()?;
+    if let Some(error) = body.error {
+        return Err(JobError::new(ErrorCode::HttpStatus, error));
+    }
+
+    let text = body
+        .translated_text
+        .ok_or_else(|| JobError::new(ErrorCode::Parse, "missing translatedText"))?;
+
+    Ok(Translation {
+        text,
+        source: body.detected_language.map(|detected| detected.language),
+    })
+}
+
+/// Translates `input` into `language` with the configured backend.
 pub fn translate(
-    amxident: AmxIdent,
-    playerid: u32,
+    config: &TranslateConfig,
     input: &str,
     language: &str,
-    encoded: u32,
+    encoded: bool,
+) -> Result<Translation, JobError> {
+    match config.backend {
+        TranslateBackend::Local => translate_local(config, input, language, encoded),
+        TranslateBackend::LibreTranslate => translate_libre(config, input, language, encoded),
+    }
+}
+
+pub struct TranslateJob {
+    config: TranslateConfig,
+    cache: Arc<Mutex<TranslationCache>>,
+    player_id: u32,
+    input: String,
+    language: String,
+    encoded: bool,
     offset: u32,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let search;
-    if encoded == 1 {
-        search = format!(
-            "{}?lang={}&q={}&encoded={}",
-            TRANSLATE,
-            encode(language),
-            input,
-            true
-        );
-    } else {
-        search = format!(
-            "{}?lang={}&q={}&encoded={}",
-            TRANSLATE,
-            encode(language),
-            encode(input),
-            false
-        );
-    }
-    let response = reqwest::blocking::get(&search)?.text()?;
-    let amx = samp::amx::get(amxident).ok_or(samp::error::AmxError::NotFound)?;
-    let index = amx.find_public("OnTranslateResponse").unwrap();
-    let allocator = amx.allocator();
-    amx.push(offset)?;
-    amx.push(allocator.allot_string(&response)?)?;
-    amx.push(playerid)?; // playerid
-    match amx.exec(index) {
-        Ok(_res) => (),
-        Err(_e) => (),
-    }
-    Ok(())
+    response: Option<Translation>,
+}
+
+impl Job for TranslateJob {
+    const CALLBACK: &'static str = "OnTranslateResponse";
+    const ERROR_CALLBACK: &'static str = "OnTranslateError";
+    const KIND: &'static str = "translate";
+
+    fn player_id(&self) -> Option<u32> {
+        Some(self.player_id)
+    }
+
+    fn executor(&mut self) -> Result<(), JobError> {
+        let translation = translate(&self.config, &self.input, &self.language, self.encoded)?;
+
+        if let Ok(mut cache) = self.cache.lock() {
+            let text = plain_text(&self.input, self.encoded);
+            cache.insert(&self.config, &text, &self.language, translation.clone());
+        }
+        self.response = Some(translation);
+
+        Ok(())
+    }
+
+    fn push(&self, amx: &Amx, allocator: &Allocator) -> AmxResult<()> {
+        let response = self.response.as_ref().ok_or(AmxError::NotFound)?;
+
+        push_string(amx, allocator, &response.source)?;
+        amx.push(self.offset)?;
+        amx.push(allocator.allot_string(&response.text)?)?;
+        amx.push(self.player_id)
+    }
+
+    fn push_error(&self, amx: &Amx, allocator: &Allocator, error: &JobError) -> AmxResult<()> {
+        push_error(amx, allocator, self.player_id, error, self.offset)
+    }
 }
 
 impl super::Plugin {
     #[native(name = "Translate")]
     pub fn native_translater(
         &mut self,
-        _amx: &Amx,
+        amx: &Amx,
         playerid: u32,
         input_data: AmxString,
         inpt_lang: AmxString,
         encoded: u32,
         offset: u32,
-    ) -> AmxResult<bool> {
-        let input_string = input_data.to_string();
-        let input_lang = inpt_lang.to_string();
-        let amx_identifier = AmxIdent::from(_amx.amx().as_ptr());
-        std::thread::spawn(move || {
-            match translate(
-                amx_identifier,
-                playerid,
-                &input_string,
-                &input_lang,
-                encoded,
-                offset,
-            ) {
-                Err(_e) => (),
-                Ok(_) => (),
+    ) -> AmxResult<u32> {
+        if !self.config.features.translate {
+            warn!("Translate: feature is disabled");
+            return Ok(0);
+        }
+
+        let input = input_data.to_string();
+        let language = inpt_lang.to_string();
+        let text = plain_text(&input, encoded == 1);
+        let cached = self
+            .translation_cache
+            .lock()
+            .ok()
+            .and_then(|mut cache| cache.get(&text, &language));
+
+        let job = TranslateJob {
+            config: self.config.translate.clone(),
+            cache: Arc::clone(&self.translation_cache),
+            player_id: playerid,
+            input,
+            language,
+            encoded: encoded == 1,
+            offset,
+            response: None,
+        };
+
+        // A cache hit is answered on the next tick without a backend call
+        if let Some(translation) = cached {
+            let job = TranslateJob {
+                response: Some(translation),
+                ..job
             };
-        });
+            return Ok(self.translate.add_completed(amx, job));
+        }
+
+        match self.translate.add_job(&self.pool, amx, job) {
+            Ok(id) => Ok(id),
+            Err(_e) => {
+                warn!("Translate: {}", _e);
+                Ok(0)
+            }
+        }
+    }
+
+    #[native(name = "TranslateCacheFlush")]
+    pub fn native_translate_cache_flush(&mut self, _amx: &Amx) -> AmxResult<bool> {
+        match self.translation_cache.lock() {
+            Ok(mut cache) => {
+                cache.flush();
+                Ok(true)
+            }
+            Err(_) => Ok(false),
+        }
+    }
+
+    #[native(name = "TranslateCacheStats")]
+    pub fn native_translate_cache_stats(
+        &mut self,
+        _amx: &Amx,
+        mut hits: Ref<i32>,
+        mut misses: Ref<i32>,
+        mut size: Ref<i32>,
+    ) -> AmxResult<bool> {
+        let cache = match self.translation_cache.lock() {
+            Ok(cache) => cache,
+            Err(_) => return Ok(false),
+        };
+
+        *hits = cache.hits as i32;
+        *misses = cache.misses as i32;
+        *size = cache.entries.len() as i32;
         Ok(true)
     }
 }
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dimension {
+    Speed,
+    Distance,
+    Mass,
+    Currency,
+}
+
+/// `factor` converts one of this unit into the dimension's base unit.
+#[derive(Debug, Clone)]
+pub struct Unit {
+    pub dimension: Dimension,
+    pub factor: f64,
+    pub symbol: String,
+}
+
+/// Bases are m/s, metres and kilograms.
+fn builtin(name: &str) -> Option<(Dimension, f64, &'static str)> {
+    let unit = match name {
+        "m/s" | "mps" => (Dimension::Speed, 1.0, "m/s"),
+        "km/h" | "kmh" | "kph" | "kmph" => (Dimension::Speed, 1.0 / 3.6, "km/h"),
+        "mph" | "mi/h" => (Dimension::Speed, 0.44704, "mph"),
+        "ft/s" | "fps" => (Dimension::Speed, 0.3048, "ft/s"),
+        "kn" | "kt" | "knot" | "knots" => (Dimension::Speed, 1852.0 / 3600.0, "kn"),
+
+        "mm" | "millimeter" | "millimeters" | "millimetre" | "millimetres" => {
+            (Dimension::Distance, 0.001, "mm")
+        }
+        "cm" | "centimeter" | "centimeters" | "centimetre" | "centimetres" => {
+            (Dimension::Distance, 0.01, "cm")
+        }
+        "m" | "meter" | "meters" | "metre" | "metres" => (Dimension::Distance, 1.0, "m"),
+        "km" | "kilometer" | "kilometers" | "kilometre" | "kilometres" => {
+            (Dimension::Distance, 1000.0, "km")
+        }
+        "in" | "inch" | "inches" => (Dimension::Distance, 0.0254, "in"),
+        "ft" | "foot" | "feet" => (Dimension::Distance, 0.3048, "ft"),
+        "yd" | "yard" | "yards" => (Dimension::Distance, 0.9144, "yd"),
+        "mi" | "mile" | "miles" => (Dimension::Distance, 1609.344, "mi"),
+        "nmi" => (Dimension::Distance, 1852.0, "nmi"),
+
+        "mg" | "milligram" | "milligrams" => (Dimension::Mass, 0.000_001, "mg"),
+        "g" | "gram" | "grams" => (Dimension::Mass, 0.001, "g"),
+        "kg" | "kilo" | "kilos" | "kilogram" | "kilograms" => (Dimension::Mass, 1.0, "kg"),
+        "t" | "ton" | "tons" | "tonne" | "tonnes" => (Dimension::Mass, 1000.0, "t"),
+        "lb" | "lbs" | "pound" | "pounds" => (Dimension::Mass, 0.453_592_37, "lb"),
+        "oz" | "ounce" | "ounces" => (Dimension::Mass, 0.028_349_523_125, "oz"),
+        "st" | "stone" | "stones" => (Dimension::Mass, 6.350_293_18, "st"),
+        _ => return None,
+    };
+    Some(unit)
+}
+
+/// Looks a unit up by any of its names, currencies come from `math.currencies`.
+pub fn lookup(name: &str, currencies: &HashMap<String, f64>) -> Option<Unit> {
+    let name = name.trim().to_lowercase();
+
+    if let Some((dimension, factor, symbol)) = builtin(&name) {
+        return Some(Unit {
+            dimension,
+            factor,
+            symbol: symbol.to_string(),
+        });
+    }
+
+    currencies
+        .iter()
+        .find(|(code, _)| code.to_lowercase() == name)
+        .map(|(code, rate)| Unit {
+            dimension: Dimension::Currency,
+            factor: *rate,
+            symbol: code.to_uppercase(),
+        })
+}
+
+/// `120 km/h to mph` split into the amount expression and both units.
+pub struct Conversion {
+    pub expression: String,
+    pub from: Unit,
+    pub to: Unit,
+}
+
+pub enum ParsedQuery {
+    /// No `to` or `in` target, evaluate as a plain expression.
+    Plain(String),
+    Conversion(Conversion),
+    /// Looked like a conversion but a unit is unknown or they don't match.
+    Invalid(String),
+}
+
+/// Turns `5% of 25000` into `5/100*25000`, a lone `%` stays modulo.
+pub fn expand_percent(query: &str) -> String {
+    static PERCENT: OnceLock<Regex> = OnceLock::new();
+    let percent = PERCENT.get_or_init(|| Regex::new(r"(?i)%\s*of\b").unwrap());
+    percent.replace_all(query, "/100*").to_string()
+}
+
+pub fn parse_query(query: &str, currencies: &HashMap<String, f64>) -> ParsedQuery {
+    static CONVERSION: OnceLock<Regex> = OnceLock::new();
+    let conversion = CONVERSION.get_or_init(|| {
+        Regex::new(r"(?i)^(.+?)\s*([a-z][a-z/]*)\s+(?:to|in)\s+([a-z][a-z/]*)\s*$").unwrap()
+    });
+
+    let captures = match conversion.captures(query.trim()) {
+        Some(captures) => captures,
+        None => return ParsedQuery::Plain(query.to_string()),
+    };
+
+    let from = match lookup(&captures[2], currencies) {
+        Some(unit) => unit,
+        None => return ParsedQuery::Invalid(format!("unknown unit \"{}\"", &captures[2])),
+    };
+    let to = match lookup(&captures[3], currencies) {
+        Some(unit) => unit,
+        None => return ParsedQuery::Invalid(format!("unknown unit \"{}\"", &captures[3])),
+    };
+
+    if from.dimension != to.dimension {
+        return ParsedQuery::Invalid(format!("cannot convert {} to {}", from.symbol, to.symbol));
+    }
+
+    ParsedQuery::Conversion(Conversion {
+        expression: captures[1].to_string(),
+        from,
+        to,
+    })
+}
+
+impl Conversion {
+    pub fn convert(&self, amount: f64) -> f64 {
+        amount * self.from.factor / self.to.factor
+    }
+}
+
+/// Rounds to `decimals` places and groups the integer digits with `separator`.
+pub fn format_number(value: f64, decimals: usize, separator: &str) -> String {
+    let text = format!("{:.*}", decimals, value.abs());
+    let (integer, fraction) = match text.find('.') {
+        Some(dot) => (&text[..dot], &text[dot..]),
+        None => (&text[..], ""),
+    };
+
+    let mut grouped = String::new();
+    for (i, digit) in integer.chars().enumerate() {
+        if i > 0 && (integer.len() - i) % 3 == 0 {
+            grouped.push_str(separator);
+        }
+        grouped.push(digit);
+    }
+
+    // -0.00 reads as 0.00
+    let is_zero = text.chars().all(|c| c == '0' || c == '.');
+    let sign = if value < 0.0 && !is_zero { "-" } else { "" };
+
+    format!("{}{}{}", sign, grouped, fraction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::evaluate;
+
+    fn currencies() -> HashMap<String, f64> {
+        let mut currencies = HashMap::new();
+        currencies.insert("usd".to_string(), 1.0);
+        currencies.insert("eur".to_string(), 1.08);
+        currencies
+    }
+
+    fn convert(query: &str) -> String {
+        match parse_query(query, &currencies()) {
+            ParsedQuery::Conversion(conversion) => {
+                let amount = evaluate(&conversion.expression).unwrap();
+                format_number(conversion.convert(amount), 2, ",")
+            }
+            ParsedQuery::Plain(_) => panic!("{} is not a conversion", query),
+            ParsedQuery::Invalid(message) => panic!("{}: {}", query, message),
+        }
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(convert("120 km/h to mph"), "74.56");
+        assert_eq!(convert("10 mi in km"), "16.09");
+        assert_eq!(convert("(2 + 3) kg to lbs"), "11.02");
+        assert_eq!(convert("100 EUR to usd"), "108.00");
+    }
+
+    #[test]
+    fn invalid_conversions() {
+        assert!(matches!(
+            parse_query("5 kg to mph", &currencies()),
+            ParsedQuery::Invalid(_)
+        ));
+        assert!(matches!(
+            parse_query("5 parsecs to km", &currencies()),
+            ParsedQuery::Invalid(_)
+        ));
+        assert!(matches!(
+            parse_query("2 + 2", &currencies()),
+            ParsedQuery::Plain(_)
+        ));
+    }
+
+    #[test]
+    fn percent_of() {
+        let query = expand_percent("5% of 25000");
+        assert_eq!(format_number(evaluate(&query).unwrap(), 2, ","), "1,250.00");
+        assert_eq!(expand_percent("7 % 4"), "7 % 4");
+    }
+
+    #[test]
+    fn number_formatting() {
+        assert_eq!(format_number(999.999, 2, ","), "1,000.00");
+        assert_eq!(format_number(1234567.891, 2, ","), "1,234,567.89");
+        assert_eq!(format_number(-1234.5, 1, " "), "-1 234.5");
+        assert_eq!(format_number(-0.001, 2, ","), "0.00");
+        assert_eq!(format_number(12.0, 0, ","), "12");
+    }
+}